@@ -0,0 +1,108 @@
+#![allow(clippy::needless_return)]
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::join_all;
+use scylla::connection::ConnectionConfig;
+use scylla::{Connection, Query};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+const STARTUP: u8 = 0x01;
+const READY: u8 = 0x02;
+const OPTIONS: u8 = 0x05;
+const SUPPORTED: u8 = 0x06;
+const RESULT: u8 = 0x08;
+
+// Answers OPTIONS with no options, STARTUP with READY and every other request with a Void result
+async fn serve(stream: TcpStream) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    let (read_half, write_half) = stream.into_split();
+    let (mut reader, mut writer) = (BufReader::new(read_half), BufWriter::new(write_half));
+
+    loop {
+        let mut header = [0u8; 9];
+        reader.read_exact(&mut header).await?;
+        let body_length = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        let mut body = vec![0u8; body_length as usize];
+        reader.read_exact(&mut body).await?;
+
+        let (opcode, body): (u8, &[u8]) = match header[4] {
+            OPTIONS => (SUPPORTED, &[0, 0]),
+            STARTUP => (READY, &[]),
+            _ => (RESULT, &[0, 0, 0, 1]),
+        };
+        writer
+            .write_all(&[0x84, 0, header[2], header[3], opcode])
+            .await?;
+        writer.write_all(&(body.len() as u32).to_be_bytes()).await?;
+        writer.write_all(body).await?;
+
+        // Like the client, flush once all requests read so far are answered
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+fn start_server(runtime: &Runtime) -> SocketAddr {
+    return runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream));
+            }
+        });
+        return address;
+    });
+}
+
+fn bench_queries(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let address = start_server(&runtime);
+    let connection = runtime.block_on(Connection::new(address)).unwrap();
+    // Baseline writing and flushing every frame on its own
+    let per_frame_config = ConnectionConfig {
+        coalesce_writes: false,
+        ..Default::default()
+    };
+    let per_frame_connection = runtime
+        .block_on(Connection::new_with_config(address, per_frame_config))
+        .unwrap();
+
+    let mut group = c.benchmark_group("queries");
+    for concurrency in [1usize, 16, 256, 1024, 4096].iter() {
+        group.throughput(Throughput::Elements(*concurrency as u64));
+        for (name, connection) in [
+            ("concurrent", &connection),
+            ("concurrent_per_frame_flush", &per_frame_connection),
+        ]
+        .iter()
+        {
+            group.bench_with_input(
+                BenchmarkId::new(*name, concurrency),
+                concurrency,
+                |b, &concurrency| {
+                    b.iter(|| {
+                        runtime.block_on(async {
+                            let queries = (0..concurrency)
+                                .map(|_| connection.query(Query::new("SELECT 1"), ()));
+                            for result in join_all(queries).await {
+                                result.unwrap();
+                            }
+                        })
+                    });
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_queries);
+criterion_main!(benches);
@@ -7,13 +7,13 @@ use scylla::Query;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let mut conn = Connection::new("127.0.0.1:9042").await?;
+    let conn = Connection::new("127.0.0.1:9042").await?;
 
     let query1 = Query::new("INSERT INTO ks.t(a,b,c) VALUES (1,2,'abc')");
-    let query2 = Query::new("INSERT INTO ks.t(a,b,c) VALUES (4,5,'def')");
+    let query2 = Query::new("INSERT INTO ks.t(a,b,c) VALUES (?,?,?)");
 
-    conn.query(query1).await.unwrap();
-    conn.query(query2).await.unwrap();
+    conn.query(query1, ()).await.unwrap();
+    conn.query(query2, (4, 5, "def")).await.unwrap();
 
     return Ok(());
 }
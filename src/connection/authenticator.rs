@@ -0,0 +1,111 @@
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.3)
+// Server requiring authentication answers STARTUP with AUTHENTICATE, then the client
+// exchanges SASL tokens with it until it sends AUTH_SUCCESS or an error
+
+use std::fmt;
+
+// SASL mechanism, starts a session answering the server for every new connection
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    // Name is the class of the authenticator the server uses,
+    // e.g. org.apache.cassandra.auth.PasswordAuthenticator
+    fn new_session(
+        &self,
+        authenticator_name: &str,
+    ) -> Result<Box<dyn AuthenticatorSession>, String>;
+}
+
+// Errors make opening the connection fail, with their message in the returned error
+pub trait AuthenticatorSession: Send {
+    // Token sent first, right after AUTHENTICATE
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, String>;
+
+    // Answers AUTH_CHALLENGE, mechanisms with many steps get called once for each of them
+    fn evaluate_challenge(&mut self, challenge: Option<&[u8]>) -> Result<Option<Vec<u8>>, String>;
+
+    // Called with the final token of AUTH_SUCCESS, by default it isn't checked
+    fn success(&mut self, _token: Option<&[u8]>) -> Result<(), String> {
+        return Ok(());
+    }
+}
+
+// Username and password for PasswordAuthenticator, sent in the SASL PLAIN format
+#[derive(Clone)]
+pub struct PlainTextAuthenticator {
+    username: String,
+    password: String,
+}
+
+impl PlainTextAuthenticator {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> PlainTextAuthenticator {
+        return PlainTextAuthenticator {
+            username: username.into(),
+            password: password.into(),
+        };
+    }
+
+    pub fn get_username(&self) -> &str {
+        return &self.username;
+    }
+
+    // \0username\0password, without an authorization id
+    fn token(&self) -> Vec<u8> {
+        let mut token = Vec::with_capacity(self.username.len() + self.password.len() + 2);
+        token.push(0);
+        token.extend_from_slice(self.username.as_bytes());
+        token.push(0);
+        token.extend_from_slice(self.password.as_bytes());
+        return token;
+    }
+}
+
+// Password is left out, configs get logged
+impl fmt::Debug for PlainTextAuthenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f
+            .debug_struct("PlainTextAuthenticator")
+            .field("username", &self.username)
+            .finish();
+    }
+}
+
+impl Authenticator for PlainTextAuthenticator {
+    fn new_session(
+        &self,
+        _authenticator_name: &str,
+    ) -> Result<Box<dyn AuthenticatorSession>, String> {
+        return Ok(Box::new(PlainTextSession {
+            token: self.token(),
+        }));
+    }
+}
+
+struct PlainTextSession {
+    token: Vec<u8>,
+}
+
+impl AuthenticatorSession for PlainTextSession {
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, String> {
+        return Ok(Some(self.token.clone()));
+    }
+
+    // Some servers ask for the credentials again, e.g. after agreeing on the mechanism
+    fn evaluate_challenge(&mut self, _challenge: Option<&[u8]>) -> Result<Option<Vec<u8>>, String> {
+        return Ok(Some(self.token.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text_token() {
+        let authenticator = PlainTextAuthenticator::new("user", "pass");
+        let mut session = authenticator.new_session("PasswordAuthenticator").unwrap();
+        assert_eq!(
+            session.initial_response().unwrap(),
+            Some(b"\0user\0pass".to_vec())
+        );
+        assert!(!format!("{:?}", authenticator).contains("pass"));
+    }
+}
@@ -1,115 +1,1056 @@
-use crate::Query;
+use super::handshake::{self, ConnectedTransport, ConnectionFeatures};
+use super::protocol::codec::{read_response_frame, RequestEncoder};
 use super::protocol::types::StreamId;
-use super::protocol::{Request, Response};
-use std::sync::Arc;
+use super::protocol::{Request, ResultMessage};
+use super::row_stream::{PageFetcher, PageFuture, PagedStatement, RowStream};
+use super::statements::{self, StatementSender};
 use super::streams::{StreamHandle, StreamsManager};
-use tokio::io::AsyncWriteExt;
-use tokio::io::{BufReader, BufWriter};
-use tokio::net::{TcpStream, ToSocketAddrs};
+use super::{ConnectionConfig, QueryResult, ValueList};
 use crate::QueryError;
+use crate::{Batch, PreparedStatement, Query};
+use bytes::{Bytes, BytesMut};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::ToSocketAddrs;
+use tokio::task::JoinHandle;
 
-/*
-    This is an attempt at more advanced Connection handler supporting multiple simulatous streams automatically
-    It would handle future drops before completion and beutiful error handling
-    It is bugged and non functional
-    Stream.state erronously gets set to Free somewhere before reading response
-*/
+// Number of requests that can wait for the writer task before senders have to wait
+const SENDER_CHANNEL_CAPACITY: usize = 1024;
 
+// Connection multiplexing many simultaneous requests over one socket, each on its own stream
+// Requests can be sent concurrently through &self, futures dropped before completion
+// leave their stream abandoned until the response arrives
 pub struct Connection {
     streams_manager: Arc<StreamsManager>,
     sender_channel: tokio::sync::mpsc::Sender<(Request, StreamId)>,
+    // Aborted when the connection is dropped, nothing would stop it waiting for responses
+    reader_task: JoinHandle<()>,
+    config: ConnectionConfig,
+    features: ConnectionFeatures,
 }
 
 impl Connection {
     pub async fn new<A: ToSocketAddrs>(address: A) -> Result<Self, std::io::Error> {
-        let tcp_stream: TcpStream = tokio::net::TcpStream::connect(address).await?;
-        let (tcp_read_half, tcp_write_half) = tcp_stream.into_split();
-        let (mut tcp_reader, mut tcp_writer) = (
-            BufReader::new(tcp_read_half),
-            BufWriter::new(tcp_write_half),
-        );
-        // Send startup request
-        let startup_request = Request::Startup;
-        startup_request.write(1, &mut tcp_writer).await?;
-        tcp_writer.flush().await?;
-
-        // Receive response
-        let (response, _stream_id) = Response::read(&mut tcp_reader).await?;
-        match response {
-            Response::Ready => { /* Ok connection succesfull */ }
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to connect to server - response was not Ready",
-                ))
-            }
-        };
+        return Connection::new_with_config(address, Default::default()).await;
+    }
+
+    pub async fn new_with_config<A: ToSocketAddrs>(
+        address: A,
+        config: ConnectionConfig,
+    ) -> Result<Self, std::io::Error> {
+        let connected = handshake::connect(address, &config).await?;
+        return Ok(Connection::start(connected, config));
+    }
+
+    #[cfg(unix)]
+    pub async fn new_unix<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        return Connection::new_unix_with_config(path, Default::default()).await;
+    }
+
+    #[cfg(unix)]
+    pub async fn new_unix_with_config<P: AsRef<Path>>(
+        path: P,
+        config: ConnectionConfig,
+    ) -> Result<Self, std::io::Error> {
+        let connected = handshake::connect_unix(path.as_ref(), &config).await?;
+        return Ok(Connection::start(connected, config));
+    }
+
+    // Stream is used as it is, e.g. TLS from the config isn't applied to it
+    pub async fn from_stream<S>(stream: S) -> Result<Self, std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        return Connection::from_stream_with_config(stream, Default::default()).await;
+    }
+
+    // Only protocol_version is tried, the stream can't be reopened with a lower one
+    pub async fn from_stream_with_config<S>(
+        stream: S,
+        config: ConnectionConfig,
+    ) -> Result<Self, std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let connected = handshake::connect_stream(Box::new(stream), &config).await?;
+        return Ok(Connection::start(connected, config));
+    }
+
+    // Spawns the reader and writer tasks of a transport that completed the handshake
+    fn start(connected: ConnectedTransport, config: ConnectionConfig) -> Connection {
+        // Writes aren't buffered, the writer task encodes whole batches of frames itself
+        let (tcp_reader, tcp_writer, features) = connected;
 
         let streams_manager: Arc<StreamsManager> = StreamsManager::new();
 
         // Start response receiver task
-        {
+        let reader_task = {
             let streams_manager = streams_manager.clone();
             tokio::spawn(async move {
                 let mut tcp_reader = tcp_reader; // Explicitly move tcp_reader into async task
                 loop {
                     // read response
-                    match Response::read(&mut tcp_reader).await {
-                        Ok((response, stream_id)) => {
-                            streams_manager.on_response_received(response, stream_id)
-                        }
+                    // Invalid response fails only the request it answers
+                    match read_response_frame(&mut tcp_reader).await {
+                        Ok((response, stream_id)) => streams_manager
+                            .on_response_received(response.map_err(QueryError::from), stream_id),
                         Err(io_error) => {
-                            streams_manager.on_receive_error(io_error);
+                            streams_manager.on_connection_error(io_error);
                             break;
                         }
                     }
                 }
-            });
-        }
+            })
+        };
 
         // Start request sender task
-        let (sender_channel_sender, mut sender_channel_receiver): (
-            tokio::sync::mpsc::Sender<(Request, StreamId)>,
-            tokio::sync::mpsc::Receiver<(Request, StreamId)>,
-        ) = tokio::sync::mpsc::channel(1);
+        let (sender_channel_sender, mut sender_channel_receiver) =
+            tokio::sync::mpsc::channel::<(Request, StreamId)>(SENDER_CHANNEL_CAPACITY);
         {
-            //let streams_manager = streams_manager.clone();
+            let streams_manager = streams_manager.clone();
+            let mut encoder =
+                RequestEncoder::new(features.get_protocol_version(), features.get_compression());
+            let coalesce_writes = config.coalesce_writes;
             tokio::spawn(async move {
                 let mut tcp_writer = tcp_writer;
+                // Reused for every batch, so encoding doesn't allocate once it has grown enough
+                let mut frames_buffer = BytesMut::new();
                 while let Some((request, stream_id)) = sender_channel_receiver.recv().await {
-                    request.write(stream_id, &mut tcp_writer).await.unwrap();
-                    tcp_writer.flush().await.unwrap();
-                    println!("Sent a query!");
+                    encoder.encode(&request, stream_id, &mut frames_buffer);
+
+                    // Encode everything queued in the meantime and write it all at once,
+                    // under load this sends many frames in a single syscall
+                    if coalesce_writes {
+                        while let Ok((request, stream_id)) = sender_channel_receiver.try_recv() {
+                            encoder.encode(&request, stream_id, &mut frames_buffer);
+                        }
+                    }
+                    encoder.finish(&mut frames_buffer);
+
+                    // TLS buffers records, flushing sends them, plain TCP has nothing to flush
+                    let mut write_result = tcp_writer.write_all(&frames_buffer).await;
+                    if write_result.is_ok() {
+                        write_result = tcp_writer.flush().await;
+                    }
+                    frames_buffer.clear();
+                    if let Err(io_error) = write_result {
+                        // Fails this request and all queued ones, they are all registered
+                        // Dropping the receiver makes next sends fail too
+                        streams_manager.on_connection_error(io_error);
+                        break;
+                    }
                 }
+
+                // Channel is closed once the connection is dropped, the server sees EOF
+                let _ = tcp_writer.shutdown().await;
             });
         }
 
-        return Ok(Connection {
+        return Connection {
             streams_manager,
             sender_channel: sender_channel_sender,
-        });
+            reader_task,
+            config,
+            features,
+        };
+    }
+
+    pub async fn query(
+        &self,
+        query_to_perform: Query,
+        values: impl ValueList,
+    ) -> Result<QueryResult, QueryError> {
+        return self.query_paged(query_to_perform, values, None).await;
+    }
+
+    // Fetches a single page, starting where the page with paging_state ended
+    pub async fn query_paged(
+        &self,
+        query_to_perform: Query,
+        values: impl ValueList,
+        paging_state: Option<Bytes>,
+    ) -> Result<QueryResult, QueryError> {
+        let values = values.serialized()?.into_owned();
+        let request =
+            statements::query_request(&query_to_perform, values, paging_state, &self.config);
+
+        return Ok(self
+            .send_request(request, query_to_perform.get_timeout())
+            .await?
+            .into());
+    }
+
+    // Streams rows of all pages, fetching the next page when the current one is consumed
+    // Other requests can still be sent on this connection while the stream is alive
+    pub fn query_iter(
+        &self,
+        query_to_perform: Query,
+        values: impl ValueList,
+    ) -> Result<RowStream<'_, &Connection>, QueryError> {
+        let values = values.serialized()?.into_owned();
+        return Ok(RowStream::new(
+            self,
+            PagedStatement::Query(query_to_perform, values),
+        ));
+    }
+
+    pub async fn prepare(&self, query_text: &str) -> Result<PreparedStatement, QueryError> {
+        let request: Request = Request::Prepare(query_text.to_string());
+
+        let result = self.send_request(request, None).await?;
+        return statements::prepared_statement(result, query_text);
+    }
+
+    pub async fn execute(
+        &self,
+        prepared_statement: &PreparedStatement,
+        values: impl ValueList,
+    ) -> Result<QueryResult, QueryError> {
+        return self.execute_paged(prepared_statement, values, None).await;
+    }
+
+    // Fetches a single page, starting where the page with paging_state ended
+    pub async fn execute_paged(
+        &self,
+        prepared_statement: &PreparedStatement,
+        values: impl ValueList,
+        paging_state: Option<Bytes>,
+    ) -> Result<QueryResult, QueryError> {
+        let values = values.serialized()?.into_owned();
+        let result =
+            statements::execute(&mut &*self, prepared_statement, values, paging_state).await?;
+        return Ok(result.into());
+    }
+
+    // Streams rows of all pages, fetching the next page when the current one is consumed
+    // Other requests can still be sent on this connection while the stream is alive
+    pub fn execute_iter(
+        &self,
+        prepared_statement: PreparedStatement,
+        values: impl ValueList,
+    ) -> Result<RowStream<'_, &Connection>, QueryError> {
+        let values = values.serialized()?.into_owned();
+        return Ok(RowStream::new(
+            self,
+            PagedStatement::Prepared(prepared_statement, values),
+        ));
+    }
+
+    pub async fn batch(&self, batch: &Batch) -> Result<QueryResult, QueryError> {
+        return Ok(statements::batch(&mut &*self, batch).await?.into());
+    }
+
+    // Connection is defunct once reading or writing failed, all requests sent on it will fail
+    // It should be dropped and replaced with a new one
+    pub fn is_defunct(&self) -> bool {
+        return self.streams_manager.get_connection_error().is_some();
+    }
+
+    // Connection is unhealthy when too many streams wait for responses nobody awaits,
+    // a pool should stop sending new requests on it
+    pub fn is_healthy(&self) -> bool {
+        return !self.is_defunct()
+            && self.streams_manager.get_orphaned_streams_count()
+                <= self.config.max_orphaned_streams;
     }
 
-    pub async fn query(&self, query_to_perform: Query) -> Result<(), QueryError> {
-        let request: Request = Request::Query(query_to_perform.get_query_text());
+    pub fn get_orphaned_streams_count(&self) -> usize {
+        return self.streams_manager.get_orphaned_streams_count();
+    }
+
+    // Options negotiated with the server when the connection was opened
+    pub fn get_features(&self) -> &ConnectionFeatures {
+        return &self.features;
+    }
+
+    // Statement timeout overrides the one from config
+    // Stream of a timed out request is abandoned, it's freed when the late response arrives
+    async fn send_request(
+        &self,
+        request: Request,
+        timeout: Option<Duration>,
+    ) -> Result<ResultMessage, QueryError> {
+        statements::check_protocol_version(&request, &self.features)?;
+
+        match timeout.or(self.config.request_timeout) {
+            Some(timeout) => {
+                match tokio::time::timeout(timeout, self.send_request_on_stream(request)).await {
+                    Ok(result) => return result,
+                    Err(_) => return Err(QueryError::Timeout),
+                }
+            }
+            None => return self.send_request_on_stream(request).await,
+        };
+    }
 
-        let stream_handle: StreamHandle = self.streams_manager.register_stream().await;
+    async fn send_request_on_stream(&self, request: Request) -> Result<ResultMessage, QueryError> {
+        let stream_handle: StreamHandle = self.streams_manager.register_stream().await?;
 
-        self.schedule_request_send(request, stream_handle.get_stream_id())
-            .await;
+        // Wait for space in the channel before marking the request sent,
+        // so that dropping this future never abandons a stream whose request wasn't sent
+        let permit = match self.sender_channel.reserve().await {
+            Ok(permit) => permit,
+            Err(_) => return Err(self.connection_broken_error()),
+        };
         stream_handle.mark_request_sent();
+        permit.send((request, stream_handle.get_stream_id()));
+
+        let response = stream_handle.get_response().await?;
+        return statements::result_from_response(response);
+    }
 
-        match stream_handle.get_response().await {
-            Err(io_error) => return Err(QueryError::IOError(io_error)),
-            Ok(Response::Result) => return Ok(()),
-            Ok(Response::Error(message)) => return Err(QueryError::Message(message)),
-            _ => panic!("Strange response!"),
+    // Writer task stops only after recording the connection error, so it should always be there
+    fn connection_broken_error(&self) -> QueryError {
+        match self.streams_manager.get_connection_error() {
+            Some(error) => return QueryError::ConnectionBroken(error),
+            None => {
+                return QueryError::ConnectionBroken(Arc::new(std::io::Error::other(
+                    "Connection writer has stopped",
+                )))
+            }
         };
     }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+impl StatementSender for &Connection {
+    fn get_config(&self) -> &ConnectionConfig {
+        return &self.config;
+    }
+
+    async fn send(
+        &mut self,
+        request: Request,
+        timeout: Option<Duration>,
+    ) -> Result<ResultMessage, QueryError> {
+        return self.send_request(request, timeout).await;
+    }
+
+    async fn prepare(&mut self, query_text: &str) -> Result<PreparedStatement, QueryError> {
+        return Connection::prepare(self, query_text).await;
+    }
+}
+
+impl<'a> PageFetcher<'a> for &'a Connection {
+    fn fetch_page(
+        self,
+        statement: PagedStatement,
+        paging_state: Option<Bytes>,
+    ) -> PageFuture<'a, Self> {
+        return Box::pin(async move {
+            let result = match statement {
+                PagedStatement::Query(query, values) => {
+                    self.query_paged(query, values, paging_state).await
+                }
+                PagedStatement::Prepared(prepared, values) => {
+                    self.execute_paged(&prepared, values, paging_state).await
+                }
+            };
+            return (self, result);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::test_utils::*;
+    use crate::connection::Unset;
+    use crate::{Authenticator, AuthenticatorSession, PlainTextAuthenticator, ProtocolVersion};
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    // Single cell row result with an int column, value is the given byte
+    fn one_row_result(value: u8) -> Vec<u8> {
+        // no_metadata, one column, one row
+        return vec![
+            0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, value,
+        ];
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        return tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+    }
+
+    // Starts with v5, test servers don't advertise it for an upgrade from v4
+    fn v5_config() -> ConnectionConfig {
+        return ConnectionConfig {
+            protocol_version: ProtocolVersion::V5,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_concurrent_requests_get_out_of_order_responses() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                let mut requests = vec![];
+                for _ in 0..3 {
+                    let (stream_id, _, body) = read_request(&mut stream).await;
+                    requests.push((stream_id, body));
+                }
+                let stream_ids: Vec<i16> = requests.iter().map(|(id, _)| *id).collect();
+                assert_eq!(stream_ids.len(), 3);
+                assert!(stream_ids.iter().all(|id| *id >= 0));
+
+                // Answer in reverse order, each with the last byte of its query text
+                for (stream_id, body) in requests.into_iter().rev() {
+                    write_response(&mut stream, stream_id, RESULT, &one_row_result(body[4])).await;
+                }
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+            let (a, b, c) = tokio::join!(
+                connection.query(Query::new("a"), ()),
+                connection.query(Query::new("b"), ()),
+                connection.query(Query::new("c"), ()),
+            );
+
+            for (result, text) in [(a, b'a'), (b, b'b'), (c, b'c')].iter() {
+                let rows = result.as_ref().unwrap().rows.as_ref().unwrap();
+                assert_eq!(rows.rows[0].get_raw(0), Some(&[*text][..]));
+            }
+
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_invalid_response_fails_only_its_request() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                let (first_id, _, _) = read_request(&mut stream).await;
+                let (second_id, _, _) = read_request(&mut stream).await;
+                // RESULT of an unknown kind
+                write_response(&mut stream, first_id, RESULT, &[0, 0, 0, 0x7f]).await;
+                write_response(&mut stream, second_id, RESULT, &one_row_result(7)).await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+            let (a, b) = tokio::join!(
+                connection.query(Query::new("a"), ()),
+                connection.query(Query::new("b"), ()),
+            );
+
+            // Requests are sent in order, so the first stream is the one of a
+            assert!(matches!(a, Err(QueryError::IOError(_))));
+            assert_eq!(b.unwrap().rows.unwrap().rows[0].get_raw(0), Some(&[7][..]));
+            assert!(!connection.is_defunct());
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    // Polls until late responses have freed all abandoned streams
+    async fn wait_for_orphans_freed(connection: &Connection) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while connection.get_orphaned_streams_count() > 0 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "late response didn't free its stream"
+            );
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[test]
+    fn test_dropped_request_frees_stream_after_response() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let (abandoned_sender, abandoned_receiver) = tokio::sync::oneshot::channel::<()>();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                // The first request is abandoned before its response is written
+                let (first_id, _, _) = read_request(&mut stream).await;
+                abandoned_receiver.await.unwrap();
+                write_response(&mut stream, first_id, RESULT, &VOID_RESULT).await;
+
+                // The stream is reused once the late response frees it
+                let (second_id, _, _) = read_request(&mut stream).await;
+                assert_eq!(second_id, first_id);
+                write_response(&mut stream, second_id, RESULT, &one_row_result(7)).await;
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+
+            let abandoned = connection.query(Query::new("a"), ());
+            let timed_out = tokio::time::timeout(Duration::from_millis(50), abandoned).await;
+            assert!(timed_out.is_err());
+            abandoned_sender.send(()).unwrap();
+
+            wait_for_orphans_freed(&connection).await;
+
+            let result = connection.query(Query::new("b"), ()).await.unwrap();
+            assert_eq!(result.rows.unwrap().rows[0].get_raw(0), Some(&[7][..]));
+
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_broken_connection_is_defunct_and_fails_requests() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                // Close the connection without answering
+                read_request(&mut stream).await;
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+            assert!(!connection.is_defunct());
+
+            let in_flight = connection.query(Query::new("a"), ()).await;
+            assert!(matches!(in_flight, Err(QueryError::ConnectionBroken(_))));
+            assert!(connection.is_defunct());
+
+            let after_break = connection.query(Query::new("b"), ()).await;
+            assert!(matches!(after_break, Err(QueryError::ConnectionBroken(_))));
+
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_timed_out_request_orphans_stream_until_response() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let (timed_out_sender, timed_out_receiver) = tokio::sync::oneshot::channel::<()>();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                let (stream_id, _, _) = read_request(&mut stream).await;
+                timed_out_receiver.await.unwrap();
+                write_response(&mut stream, stream_id, RESULT, &VOID_RESULT).await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let config = ConnectionConfig {
+                max_orphaned_streams: 0,
+                ..Default::default()
+            };
+            let connection = Connection::new_with_config(address, config).await.unwrap();
+
+            let mut query = Query::new("a");
+            query.set_timeout(Some(Duration::from_millis(50)));
+            let result = connection.query(query, ()).await;
+            assert!(matches!(result, Err(QueryError::Timeout)));
+            assert_eq!(connection.get_orphaned_streams_count(), 1);
+            assert!(!connection.is_healthy());
+
+            timed_out_sender.send(()).unwrap();
+            let _server_stream = server.await.unwrap();
+
+            wait_for_orphans_freed(&connection).await;
+            assert!(connection.is_healthy());
+        });
+    }
+
+    #[test]
+    fn test_oversized_response_breaks_connection() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                let (stream_id, _, _) = read_request(&mut stream).await;
+                write_response(&mut stream, stream_id, RESULT, &[0u8; 128]).await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let config = ConnectionConfig {
+                max_frame_size: 64,
+                ..Default::default()
+            };
+            let connection = Connection::new_with_config(address, config).await.unwrap();
+
+            match connection.query(Query::new("a"), ()).await {
+                Err(QueryError::ConnectionBroken(error)) => {
+                    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData)
+                }
+                other => panic!("Unexpected result: {:?}", other),
+            };
+            assert!(connection.is_defunct());
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_startup_uses_options_from_supported() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let supported: &[(&str, &[&str])] = &[
+                    ("CQL_VERSION", &["3.2.1", "3.4.5"]),
+                    ("SCYLLA_SHARD", &["3"]),
+                ];
+                let startup_body = accept_startup_with_options(&mut stream, supported).await;
+                return (stream, startup_body);
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+            let (_server_stream, startup_body) = server.await.unwrap();
+
+            let expected_startup_body = [
+                0u8, 1, 0, 11, b'C', b'Q', b'L', b'_', b'V', b'E', b'R', b'S', b'I', b'O', b'N', 0,
+                5, b'3', b'.', b'4', b'.', b'5',
+            ];
+            assert_eq!(startup_body, expected_startup_body);
+
+            let features = connection.get_features();
+            assert_eq!(features.get_cql_version(), "3.4.5");
+            assert_eq!(features.get_compression(), None);
+            assert_eq!(features.get_extensions()["SCYLLA_SHARD"], ["3"]);
+        });
+    }
+
+    #[test]
+    fn test_rejected_protocol_version_falls_back_to_lower_one() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                // Server speaking only v3 rejects OPTIONS and closes the connection
+                assert_eq!(reject_protocol_version(&listener, 0x83).await, 0x05);
+                assert_eq!(reject_protocol_version(&listener, 0x83).await, 0x04);
+
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accept_startup(&mut stream).await;
+
+                let (header, _) = read_frame(&mut stream).await;
+                assert_eq!(header.protocol_version, 0x03);
+                write_versioned_response(&mut stream, 0x83, header.stream_id, RESULT, &VOID_RESULT)
+                    .await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let connection = Connection::new_with_config(address, v5_config())
+                .await
+                .unwrap();
+            assert_eq!(
+                connection.get_features().get_protocol_version(),
+                ProtocolVersion::V3
+            );
+            connection.query(Query::new("a"), ()).await.unwrap();
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_rejected_beta_version_falls_back_to_lower_one() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                // Cassandra 3.11 knows v5 only as a beta version
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (header, _) = read_frame(&mut stream).await;
+                assert_eq!(header.protocol_version, 0x05);
+                let message =
+                    "Beta version of the protocol used (5/v5-beta), but USE_BETA flag is unset";
+                reject_options_with_message(&mut stream, 0x84, header.stream_id, message).await;
+
+                return accept_connection(&listener).await;
+            });
+
+            let connection = Connection::new_with_config(address, v5_config())
+                .await
+                .unwrap();
+            assert_eq!(
+                connection.get_features().get_protocol_version(),
+                ProtocolVersion::V4
+            );
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_v5_frames_are_sent_in_segments() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accept_startup(&mut stream).await;
+
+                let (header, body) = read_segmented_frame(&mut stream).await;
+                assert_eq!(header.protocol_version, 0x05);
+                // query text, consistency, flags as [int] with now_in_seconds, now_in_seconds
+                assert_eq!(body, [0, 0, 0, 1, b'a', 0, 1, 0, 0, 1, 0, 0, 0, 0, 42]);
+                write_segmented_response(&mut stream, header.stream_id, RESULT, &one_row_result(7))
+                    .await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let connection = Connection::new_with_config(address, v5_config())
+                .await
+                .unwrap();
+            assert_eq!(
+                connection.get_features().get_protocol_version(),
+                ProtocolVersion::V5
+            );
+
+            let mut query = Query::new("a");
+            query.set_now_in_seconds(Some(42));
+            let result = connection.query(query, ()).await.unwrap();
+            assert_eq!(result.rows.unwrap().rows[0].get_raw(0), Some(&[7][..]));
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_advertised_newer_version_is_reconnected_with() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let supported: &[(&str, &[&str])] =
+                    &[("PROTOCOL_VERSIONS", &["3/v3", "4/v4", "5/v5", "6/v6-beta"])];
+                // Client doesn't send STARTUP after learning about v5
+                assert_eq!(answer_options(&mut stream, supported).await, 0x04);
+
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accept_startup(&mut stream).await;
+                let (header, _) = read_segmented_frame(&mut stream).await;
+                assert_eq!(header.protocol_version, 0x05);
+                write_segmented_response(&mut stream, header.stream_id, RESULT, &VOID_RESULT).await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+            assert_eq!(
+                connection.get_features().get_protocol_version(),
+                ProtocolVersion::V5
+            );
+            connection.query(Query::new("a"), ()).await.unwrap();
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
 
-    async fn schedule_request_send(&self, request: Request, stream_id: StreamId) {
-        if let Err(_) = self.sender_channel.clone().send((request, stream_id)).await {
-            panic!("oops ending request failed"); //TODO make graceful
+    #[test]
+    fn test_v5_options_need_v5_connection() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                // Keep the connection open, the query must not reach it
+                return accept_connection(&listener).await;
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+            let mut query = Query::new("a");
+            query.set_keyspace(Some("ks".to_string()));
+            let result = connection.query(query, ()).await;
+            assert!(matches!(
+                result,
+                Err(QueryError::UnsupportedByProtocolVersion(
+                    ProtocolVersion::V4
+                ))
+            ));
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_unset_values_need_v4_connection() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                // Keep the connection open, the query must not reach it
+                return accept_connection(&listener).await;
+            });
+
+            let config = ConnectionConfig {
+                protocol_version: ProtocolVersion::V3,
+                ..Default::default()
+            };
+            let connection = Connection::new_with_config(address, config).await.unwrap();
+            let result = connection.query(Query::new("a"), (1i32, Unset)).await;
+            assert!(matches!(
+                result,
+                Err(QueryError::UnsupportedByProtocolVersion(
+                    ProtocolVersion::V3
+                ))
+            ));
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    // Answers every challenge with its bytes reversed
+    #[derive(Debug)]
+    struct ReversingAuthenticator;
+
+    impl Authenticator for ReversingAuthenticator {
+        fn new_session(
+            &self,
+            authenticator_name: &str,
+        ) -> Result<Box<dyn AuthenticatorSession>, String> {
+            assert_eq!(authenticator_name, "Auth");
+            return Ok(Box::new(ReversingAuthenticator));
+        }
+    }
+
+    impl AuthenticatorSession for ReversingAuthenticator {
+        fn initial_response(&mut self) -> Result<Option<Vec<u8>>, String> {
+            return Ok(Some(b"hi".to_vec()));
         }
+
+        fn evaluate_challenge(
+            &mut self,
+            challenge: Option<&[u8]>,
+        ) -> Result<Option<Vec<u8>>, String> {
+            return Ok(challenge.map(|challenge| challenge.iter().rev().copied().collect()));
+        }
+    }
+
+    // AUTHENTICATE with the authenticator name "Auth"
+    const AUTHENTICATE_BODY: [u8; 6] = [0, 4, b'A', b'u', b't', b'h'];
+
+    #[test]
+    fn test_authentication_answers_challenges() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                answer_startup(&mut stream, &[], AUTHENTICATE, &AUTHENTICATE_BODY).await;
+
+                let (stream_id, opcode, body) = read_request(&mut stream).await;
+                assert_eq!((opcode, body), (0x0F, vec![0, 0, 0, 2, b'h', b'i']));
+                write_response(&mut stream, stream_id, AUTH_CHALLENGE, &[0, 0, 0, 2, 1, 2]).await;
+
+                let (stream_id, _, body) = read_request(&mut stream).await;
+                assert_eq!(body, [0, 0, 0, 2, 2, 1]);
+                write_response(
+                    &mut stream,
+                    stream_id,
+                    AUTH_SUCCESS,
+                    &[0xff, 0xff, 0xff, 0xff],
+                )
+                .await;
+
+                let (stream_id, _, _) = read_request(&mut stream).await;
+                write_response(&mut stream, stream_id, RESULT, &VOID_RESULT).await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let config = ConnectionConfig {
+                authenticator: Some(Arc::new(ReversingAuthenticator)),
+                ..Default::default()
+            };
+            let connection = Connection::new_with_config(address, config).await.unwrap();
+            connection.query(Query::new("a"), ()).await.unwrap();
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_failed_authentication_fails_connecting() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                // Client without an authenticator gives up after AUTHENTICATE
+                let (mut stream, _) = listener.accept().await.unwrap();
+                answer_startup(&mut stream, &[], AUTHENTICATE, &AUTHENTICATE_BODY).await;
+
+                let (mut stream, _) = listener.accept().await.unwrap();
+                answer_startup(&mut stream, &[], AUTHENTICATE, &AUTHENTICATE_BODY).await;
+                let (stream_id, _, body) = read_request(&mut stream).await;
+                assert_eq!(body[4..], b"\0user\0wrong"[..]);
+                let bad_credentials = [0, 0, 0x01, 0, 0, 3, b'b', b'a', b'd'];
+                write_response(&mut stream, stream_id, ERROR, &bad_credentials).await;
+            });
+
+            let error = Connection::new(address).await.err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+
+            let config = ConnectionConfig {
+                authenticator: Some(Arc::new(PlainTextAuthenticator::new("user", "wrong"))),
+                ..Default::default()
+            };
+            let error = Connection::new_with_config(address, config)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+            assert!(error.to_string().ends_with("bad"));
+
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_dropped_connection_closes_socket() {
+        runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+                let mut buf = [0u8; 1];
+                return stream.read(&mut buf).await.unwrap();
+            });
+
+            let connection = Connection::new(address).await.unwrap();
+            drop(connection);
+
+            let read = tokio::time::timeout(Duration::from_secs(5), server).await;
+            assert_eq!(read.unwrap().unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn test_connection_from_stream() {
+        runtime().block_on(async {
+            let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+            let server = tokio::spawn(async move {
+                accept_startup(&mut server_stream).await;
+
+                let (header, _) = read_segmented_frame(&mut server_stream).await;
+                write_segmented_response(
+                    &mut server_stream,
+                    header.stream_id,
+                    RESULT,
+                    &VOID_RESULT,
+                )
+                .await;
+
+                // Keep the connection open
+                return server_stream;
+            });
+
+            let connection = Connection::from_stream_with_config(client_stream, v5_config())
+                .await
+                .unwrap();
+            connection.query(Query::new("a"), ()).await.unwrap();
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_stream_isnt_reused_for_lower_protocol_version() {
+        runtime().block_on(async {
+            let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+            let server = tokio::spawn(async move {
+                reject_options(&mut server_stream, 0x84).await;
+                return server_stream;
+            });
+
+            assert!(
+                Connection::from_stream_with_config(client_stream, v5_config())
+                    .await
+                    .is_err()
+            );
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_unix_socket_connection() {
+        let path = std::env::temp_dir().join(format!("scylla-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        runtime().block_on(async {
+            let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+            let server = tokio::spawn(async move {
+                // Lower protocol version is tried on a new connection, like over TCP
+                let (mut stream, _) = listener.accept().await.unwrap();
+                assert_eq!(reject_options(&mut stream, 0x84).await, 0x05);
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accept_startup(&mut stream).await;
+
+                let (stream_id, _, _) = read_request(&mut stream).await;
+                write_response(&mut stream, stream_id, RESULT, &VOID_RESULT).await;
+
+                // Keep the connection open
+                return stream;
+            });
+
+            let connection = Connection::new_unix_with_config(&path, v5_config())
+                .await
+                .unwrap();
+            connection.query(Query::new("a"), ()).await.unwrap();
+
+            let _server_stream = server.await.unwrap();
+        });
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
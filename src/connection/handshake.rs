@@ -0,0 +1,490 @@
+use super::protocol::codec::{read_response, response_reader, RequestEncoder, ResponseReader};
+use super::protocol::request::StartupOptions;
+use super::protocol::{Request, Response};
+use super::transport::{self, Transport, TransportReadHalf, TransportWriteHalf};
+use super::{Compression, ConnectionConfig, ErrorMessage, ProtocolVersion};
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::future::Future;
+#[cfg(unix)]
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+// CQL_VERSION sent when the server doesn't advertise any
+const DEFAULT_CQL_VERSION: &str = "3.0.0";
+
+// STARTUP options negotiated with the server and everything else it advertised in SUPPORTED
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionFeatures {
+    protocol_version: ProtocolVersion,
+    cql_version: String,
+    compression: Option<Compression>,
+    protocol_versions: Vec<String>,
+    extensions: HashMap<String, Vec<String>>,
+}
+
+impl ConnectionFeatures {
+    // Picks the newest CQL version and the configured compression if the server supports it,
+    // unsupported compression falls back to uncompressed frames
+    // Since v5 only compression that can be used for segments is picked
+    fn negotiate(
+        mut supported: HashMap<String, Vec<String>>,
+        protocol_version: ProtocolVersion,
+        config: &ConnectionConfig,
+    ) -> ConnectionFeatures {
+        let cql_version = supported
+            .remove("CQL_VERSION")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|version| Some((parse_version(&version)?, version)))
+            .max()
+            .map(|(_, version)| version)
+            .unwrap_or_else(|| DEFAULT_CQL_VERSION.to_string());
+
+        let supported_compressions = supported.remove("COMPRESSION").unwrap_or_default();
+        let compression = config.compression.filter(|compression| {
+            let usable =
+                protocol_version < ProtocolVersion::V5 || compression.for_segments().is_some();
+            usable
+                && supported_compressions
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(compression.as_str()))
+        });
+
+        let protocol_versions = supported.remove("PROTOCOL_VERSIONS").unwrap_or_default();
+
+        return ConnectionFeatures {
+            protocol_version,
+            cql_version,
+            compression,
+            protocol_versions,
+            extensions: supported,
+        };
+    }
+
+    // Version all frames after the handshake are encoded with
+    pub fn get_protocol_version(&self) -> ProtocolVersion {
+        return self.protocol_version;
+    }
+
+    pub fn get_cql_version(&self) -> &str {
+        return &self.cql_version;
+    }
+
+    pub fn get_compression(&self) -> Option<Compression> {
+        return self.compression;
+    }
+
+    // Protocol versions advertised by the server, e.g. "4/v4"
+    pub fn get_protocol_versions(&self) -> &[String] {
+        return &self.protocol_versions;
+    }
+
+    // Newest advertised version the driver speaks, beta ones like "5/v5-beta" are skipped,
+    // servers reject them unless the USE_BETA flag is set
+    pub fn get_newest_protocol_version(&self) -> Option<ProtocolVersion> {
+        return self
+            .protocol_versions
+            .iter()
+            .filter_map(|advertised| {
+                let (number, name) = advertised.split_once('/')?;
+                if name.contains("beta") {
+                    return None;
+                }
+                return ProtocolVersion::from_number(number.parse().ok()?);
+            })
+            .max();
+    }
+
+    // Non-standard options, e.g. Scylla's SCYLLA_SHARD, with their advertised values
+    pub fn get_extensions(&self) -> &HashMap<String, Vec<String>> {
+        return &self.extensions;
+    }
+
+    pub fn has_extension(&self, name: &str) -> bool {
+        return self.extensions.contains_key(name);
+    }
+}
+
+pub enum HandshakeError {
+    // Server doesn't speak the protocol version the handshake was attempted with
+    UnsupportedVersion(ErrorMessage),
+    // Server advertised a newer version than the handshake was attempted with
+    NewerVersionSupported(ProtocolVersion),
+    IOError(std::io::Error),
+}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(error: std::io::Error) -> HandshakeError {
+        return HandshakeError::IOError(error);
+    }
+}
+
+// Halves of a transport that completed the handshake, with the options it negotiated
+pub type ConnectedTransport = (
+    ResponseReader<TransportReadHalf>,
+    TransportWriteHalf,
+    ConnectionFeatures,
+);
+
+// Connects and performs the handshake, starting with the configured protocol version
+pub async fn connect<A: ToSocketAddrs>(
+    address: A,
+    config: &ConnectionConfig,
+) -> Result<ConnectedTransport, std::io::Error> {
+    let tcp_stream = TcpStream::connect(address).await?;
+    // Reconnect to the same address even if the name resolves to many of them
+    let peer_address = tcp_stream.peer_addr()?;
+    let transport = transport::open(tcp_stream, config).await?;
+
+    let reconnect = || async move {
+        return transport::open(TcpStream::connect(peer_address).await?, config).await;
+    };
+    return connect_with_fallback(transport, config, reconnect).await;
+}
+
+// Same as connect, over a Unix domain socket
+#[cfg(unix)]
+pub async fn connect_unix(
+    path: &Path,
+    config: &ConnectionConfig,
+) -> Result<ConnectedTransport, std::io::Error> {
+    let reconnect = || async move {
+        let unix_stream = UnixStream::connect(path).await?;
+        return Ok(Box::new(unix_stream) as Box<dyn Transport>);
+    };
+    return connect_with_fallback(reconnect().await?, config, reconnect).await;
+}
+
+// Performs the handshake on a stream opened by the caller, it's used as it is
+// Stream can't be reopened, so a rejected protocol version fails instead of trying lower ones
+// and a newer version advertised by the server isn't switched to
+pub async fn connect_stream(
+    transport: Box<dyn Transport>,
+    config: &ConnectionConfig,
+) -> Result<ConnectedTransport, std::io::Error> {
+    let version = config.protocol_version;
+    match handshake(transport, config, version, false).await {
+        Ok(connected) => return Ok(connected),
+        Err(HandshakeError::IOError(error)) => return Err(error),
+        Err(HandshakeError::NewerVersionSupported(_)) => unreachable!("upgrade wasn't allowed"),
+        Err(HandshakeError::UnsupportedVersion(error)) => {
+            return Err(std::io::Error::other(format!(
+                "Failed to connect to server - protocol version {:?} is not supported \
+                 and the stream can't be reopened to try a lower one: {}",
+                version,
+                error.get_message()
+            )))
+        }
+    };
+}
+
+// Server may close the connection after rejecting a version, so each lower one is tried
+// on a new connection
+// A newer version advertised in SUPPORTED is also connected with anew, only once,
+// so that falling back after it's rejected doesn't upgrade again
+async fn connect_with_fallback<F, Fut>(
+    mut transport: Box<dyn Transport>,
+    config: &ConnectionConfig,
+    reconnect: F,
+) -> Result<ConnectedTransport, std::io::Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Box<dyn Transport>, std::io::Error>>,
+{
+    let mut version = config.protocol_version;
+    let mut upgrade = true;
+    loop {
+        match handshake(transport, config, version, upgrade).await {
+            Ok(connected) => return Ok(connected),
+            Err(HandshakeError::IOError(error)) => return Err(error),
+            Err(HandshakeError::NewerVersionSupported(newer_version)) => {
+                version = newer_version;
+                upgrade = false;
+            }
+            Err(HandshakeError::UnsupportedVersion(error)) => match version.lower() {
+                Some(lower_version) => version = lower_version,
+                None => {
+                    return Err(std::io::Error::other(format!(
+                        "Failed to connect to server - no supported protocol version: {}",
+                        error.get_message()
+                    )))
+                }
+            },
+        };
+
+        transport = reconnect().await?;
+    }
+}
+
+async fn handshake(
+    transport: Box<dyn Transport>,
+    config: &ConnectionConfig,
+    version: ProtocolVersion,
+    upgrade: bool,
+) -> Result<ConnectedTransport, HandshakeError> {
+    let (read_half, mut write_half) = tokio::io::split(transport);
+    let mut reader = response_reader(read_half, config.max_frame_size, None);
+    let features =
+        perform_handshake(&mut reader, &mut write_half, config, version, upgrade).await?;
+    return Ok((reader, write_half, features));
+}
+
+// Sends OPTIONS, then STARTUP with the options picked from SUPPORTED,
+// authenticating when the server asks for it
+// With upgrade set, stops before STARTUP when SUPPORTED lists a newer version
+// Reader decompresses frames once compression is negotiated, since v5 it unwraps segments
+async fn perform_handshake<R, W>(
+    reader: &mut ResponseReader<R>,
+    writer: &mut W,
+    config: &ConnectionConfig,
+    version: ProtocolVersion,
+    upgrade: bool,
+) -> Result<ConnectionFeatures, HandshakeError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    reader.decoder_mut().set_protocol_version(version);
+
+    // Server checks the version with the first request, OPTIONS is the one it always accepts
+    Request::Options.write(1, writer, version, None).await?;
+    writer.flush().await?;
+
+    let supported = match read_response(reader).await?.0 {
+        Response::Supported(supported) => supported,
+        // OPTIONS has no body to get wrong, so a protocol error rejects the version
+        // Messages differ, e.g. Cassandra 3.11 speaks of a beta version for v5
+        Response::Error(error) if error.is_protocol_error() => {
+            return Err(HandshakeError::UnsupportedVersion(error))
+        }
+        _ => {
+            return Err(std::io::Error::other(
+                "Failed to connect to server - response was not Supported",
+            )
+            .into())
+        }
+    };
+    let features = ConnectionFeatures::negotiate(supported, version, config);
+    if let Some(newer_version) = features.get_newest_protocol_version() {
+        if upgrade && newer_version > version {
+            return Err(HandshakeError::NewerVersionSupported(newer_version));
+        }
+    }
+
+    let startup_request = Request::Startup(StartupOptions {
+        cql_version: features.cql_version.clone(),
+        compression: features.compression,
+    });
+    startup_request.write(1, writer, version, None).await?;
+    writer.flush().await?;
+    // Everything after STARTUP may be compressed, its own response isn't
+    reader.decoder_mut().set_compression(features.compression);
+
+    let authenticator_name = match read_response(reader).await?.0 {
+        Response::Ready => None,
+        Response::Authenticate(authenticator_name) => Some(authenticator_name),
+        _ => {
+            return Err(std::io::Error::other(
+                "Failed to connect to server - response was not Ready",
+            )
+            .into())
+        }
+    };
+    // Since v5 frames after READY or AUTHENTICATE are sent in segments
+    if version >= ProtocolVersion::V5 {
+        reader.decoder_mut().enable_segments();
+    }
+
+    if let Some(authenticator_name) = authenticator_name {
+        let mut encoder = RequestEncoder::new(version, features.compression);
+        authenticate(reader, writer, &mut encoder, config, &authenticator_name).await?;
+    }
+
+    return Ok(features);
+}
+
+// Answers the server with tokens of the configured authenticator until it accepts them
+async fn authenticate<R, W>(
+    reader: &mut ResponseReader<R>,
+    writer: &mut W,
+    encoder: &mut RequestEncoder,
+    config: &ConnectionConfig,
+    authenticator_name: &str,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let authenticator = match &config.authenticator {
+        Some(authenticator) => authenticator,
+        None => {
+            return Err(make_authentication_error(format!(
+                "server requires {}, but no authenticator is configured",
+                authenticator_name
+            )))
+        }
+    };
+    let mut session = authenticator
+        .new_session(authenticator_name)
+        .map_err(make_authentication_error)?;
+
+    let mut token = session
+        .initial_response()
+        .map_err(make_authentication_error)?;
+    loop {
+        let mut buf = BytesMut::new();
+        encoder.encode(&Request::AuthResponse(token), 1, &mut buf);
+        encoder.finish(&mut buf);
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+
+        match read_response(reader).await?.0 {
+            Response::AuthChallenge(challenge) => {
+                token = session
+                    .evaluate_challenge(challenge.as_deref())
+                    .map_err(make_authentication_error)?;
+            }
+            Response::AuthSuccess(token) => {
+                return session
+                    .success(token.as_deref())
+                    .map_err(make_authentication_error);
+            }
+            // e.g. bad credentials
+            Response::Error(error) => {
+                return Err(make_authentication_error(error.get_message().to_string()))
+            }
+            _ => {
+                return Err(make_authentication_error(
+                    "unexpected response to AUTH_RESPONSE".to_string(),
+                ))
+            }
+        };
+    }
+}
+
+fn make_authentication_error(reason: String) -> std::io::Error {
+    return std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!(
+            "Failed to connect to server - authentication failed: {}",
+            reason
+        ),
+    );
+}
+
+// Numeric components of a version like "3.4.5", so that "3.10.0" is newer than "3.4.0"
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    return version.split('.').map(|part| part.parse().ok()).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supported(options: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        return options
+            .iter()
+            .map(|(key, values)| {
+                let values = values.iter().map(|value| value.to_string()).collect();
+                (key.to_string(), values)
+            })
+            .collect();
+    }
+
+    #[test]
+    fn test_newest_cql_version_is_picked() {
+        let options = supported(&[("CQL_VERSION", &["3.4.0", "3.10.1", "bogus", "3.9.9"])]);
+        let features =
+            ConnectionFeatures::negotiate(options, ProtocolVersion::V4, &Default::default());
+        assert_eq!(features.get_cql_version(), "3.10.1");
+
+        let features =
+            ConnectionFeatures::negotiate(supported(&[]), ProtocolVersion::V4, &Default::default());
+        assert_eq!(features.get_cql_version(), DEFAULT_CQL_VERSION);
+    }
+
+    #[test]
+    fn test_extensions_exclude_standard_options() {
+        let options = supported(&[
+            ("CQL_VERSION", &["3.3.1"]),
+            ("COMPRESSION", &["lz4", "snappy"]),
+            ("PROTOCOL_VERSIONS", &["3/v3", "4/v4"]),
+            ("SCYLLA_SHARD", &["0"]),
+            (
+                "SCYLLA_LWT_ADD_METADATA_MARK",
+                &["LWT_OPTIMIZATION_META_BIT_MASK=2147483648"],
+            ),
+        ]);
+        let features =
+            ConnectionFeatures::negotiate(options, ProtocolVersion::V4, &Default::default());
+
+        assert_eq!(features.get_protocol_versions(), ["3/v3", "4/v4"]);
+        assert_eq!(features.get_extensions().len(), 2);
+        assert!(features.has_extension("SCYLLA_SHARD"));
+        assert!(!features.has_extension("COMPRESSION"));
+        // Compression wasn't configured, so it isn't used even though it's supported
+        assert_eq!(features.get_compression(), None);
+    }
+
+    #[test]
+    fn test_newest_protocol_version_skips_beta_ones() {
+        let newest = |versions: &[&str]| {
+            let options = supported(&[("PROTOCOL_VERSIONS", versions)]);
+            return ConnectionFeatures::negotiate(
+                options,
+                ProtocolVersion::V4,
+                &Default::default(),
+            )
+            .get_newest_protocol_version();
+        };
+
+        assert_eq!(newest(&["3/v3", "4/v4", "5/v5"]), Some(ProtocolVersion::V5));
+        // Cassandra 3.11
+        assert_eq!(
+            newest(&["3/v3", "4/v4", "5/v5-beta"]),
+            Some(ProtocolVersion::V4)
+        );
+        assert_eq!(
+            newest(&["4/v4", "6/v6", "bogus"]),
+            Some(ProtocolVersion::V4)
+        );
+        assert_eq!(newest(&[]), None);
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_compression_needs_server_support() {
+        let config = ConnectionConfig {
+            compression: Some(Compression::Lz4),
+            ..Default::default()
+        };
+
+        let options = supported(&[("COMPRESSION", &["snappy", "LZ4"])]);
+        let features = ConnectionFeatures::negotiate(options, ProtocolVersion::V4, &config);
+        assert_eq!(features.get_compression(), Some(Compression::Lz4));
+
+        let options = supported(&[("COMPRESSION", &["snappy"])]);
+        let features = ConnectionFeatures::negotiate(options, ProtocolVersion::V4, &config);
+        assert_eq!(features.get_compression(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "snappy")]
+    fn test_snappy_is_not_used_for_segments() {
+        let config = ConnectionConfig {
+            compression: Some(Compression::Snappy),
+            ..Default::default()
+        };
+
+        let options = supported(&[("COMPRESSION", &["snappy"])]);
+        let features = ConnectionFeatures::negotiate(options.clone(), ProtocolVersion::V4, &config);
+        assert_eq!(features.get_compression(), Some(Compression::Snappy));
+
+        let features = ConnectionFeatures::negotiate(options, ProtocolVersion::V5, &config);
+        assert_eq!(features.get_compression(), None);
+    }
+}
@@ -1,15 +1,101 @@
+mod authenticator;
+mod handshake;
 mod protocol;
+mod row_stream;
+mod statements;
 mod streams;
+#[cfg(test)]
+mod test_utils;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
 pub mod simple_connection;
 pub mod complicated_connection;
 
-pub use simple_connection::Connection;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use authenticator::{Authenticator, AuthenticatorSession, PlainTextAuthenticator};
+pub use complicated_connection::Connection;
+pub use handshake::ConnectionFeatures;
+pub use protocol::compression::Compression;
 pub use protocol::response::ErrorMessage;
+pub use protocol::request::BatchType;
+pub use protocol::result::{
+    ColumnSpec, Prepared, PreparedMetadata, QueryResult, ResultMessage, ResultMetadata, Row, Rows, SchemaChange,
+    SchemaChangeTarget, SchemaChangeType, SetKeyspace,
+};
+pub use protocol::types::{ColumnType, Consistency, ProtocolVersion, SerialConsistency};
+pub use protocol::value::{CqlValue, ParseError};
+pub use row_stream::RowStream;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+
+pub use protocol::serialize::{
+    SerializeValuesError, SerializedValues, Unset, Value, ValueList, ValueTooBig,
+};
+
+// Settings applied to all requests sent over a connection
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    // Used by statements that don't set their own consistency
+    pub default_consistency: Consistency,
+    // Used by statements that don't set their own serial consistency, None leaves it to the server
+    pub default_serial_consistency: Option<SerialConsistency>,
+    // Used by statements that don't set their own timeout, None waits forever
+    pub request_timeout: Option<Duration>,
+    // Connection is unhealthy when more streams than this wait for responses nobody awaits
+    pub max_orphaned_streams: usize,
+    // Larger responses break the connection instead of being read
+    pub max_frame_size: usize,
+    // Frames queued while the previous write was in progress are written and flushed together,
+    // false writes each one separately, which is useful only as a baseline for benchmarks
+    pub coalesce_writes: bool,
+    // Used only if the server supports it, otherwise frames are sent and received uncompressed
+    pub compression: Option<Compression>,
+    // Version the handshake starts with, lower ones are tried when the server rejects it
+    // and a newer one the server lists in PROTOCOL_VERSIONS is reconnected with
+    // Each rejected version costs another connect and OPTIONS round trip, so it defaults to v4,
+    // which Cassandra and Scylla both speak
+    pub protocol_version: ProtocolVersion,
+    // Used when the server requires authentication, connecting to such a server fails without it
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    // Connection is encrypted when set
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        return ConnectionConfig {
+            default_consistency: Default::default(),
+            default_serial_consistency: None,
+            request_timeout: None,
+            max_orphaned_streams: 1024,
+            max_frame_size: protocol::codec::DEFAULT_MAX_FRAME_SIZE,
+            coalesce_writes: true,
+            compression: None,
+            protocol_version: ProtocolVersion::V4,
+            authenticator: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+    }
+}
 
 #[derive(Debug)]
 pub enum QueryError {
     IOError(std::io::Error),
     Message(ErrorMessage),
+    BadValues(SerializeValuesError),
+    // Server sent a response that doesn't match the request
+    UnexpectedResponse,
+    // Reading from or writing to the connection failed, every request sent on it gets the same error
+    ConnectionBroken(Arc<std::io::Error>),
+    // No response came within the request timeout
+    Timeout,
+    // Statement uses options the protocol version negotiated with the server doesn't have
+    UnsupportedByProtocolVersion(ProtocolVersion),
 }
 
 impl From<std::io::Error> for QueryError {
@@ -17,3 +103,9 @@ impl From<std::io::Error> for QueryError {
         return QueryError::IOError(io_error);
     }
 }
+
+impl From<SerializeValuesError> for QueryError {
+    fn from(values_error: SerializeValuesError) -> QueryError {
+        return QueryError::BadValues(values_error);
+    }
+}
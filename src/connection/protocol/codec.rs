@@ -0,0 +1,357 @@
+use super::compression::{Compression, SegmentCompression};
+use super::segment;
+use super::types::ProtocolVersion;
+use super::{Header, Request, Response, StreamId};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use tokio::io::AsyncRead;
+use tokio_util::codec::{Decoder, FramedRead};
+
+// Protocol limit on the size of a frame, including its header
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+// Splits complete frames out of the read buffer
+// Frame body is split off the buffer without copying and the response keeps pointing into it,
+// unless it has to be decompressed
+pub struct ResponseCodec {
+    max_frame_size: usize,
+    // Negotiated in STARTUP, used for frames with the compression flag
+    compression: Option<Compression>,
+    protocol_version: ProtocolVersion,
+    // Since v5 frames after the handshake arrive in segments, unwrapped into frames
+    segmented: bool,
+    frames: BytesMut,
+}
+
+impl ResponseCodec {
+    pub fn new(max_frame_size: usize, compression: Option<Compression>) -> ResponseCodec {
+        return ResponseCodec {
+            max_frame_size,
+            compression,
+            protocol_version: ProtocolVersion::V4,
+            segmented: false,
+            frames: BytesMut::new(),
+        };
+    }
+
+    // Compression is only known after the handshake, frames before it are never compressed
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    // Responses in other versions are rejected, except errors
+    pub fn set_protocol_version(&mut self, protocol_version: ProtocolVersion) {
+        self.protocol_version = protocol_version;
+    }
+
+    // Responses after the v5 handshake come in segments, compressed instead of the frames
+    pub fn enable_segments(&mut self) {
+        self.segmented = true;
+    }
+
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<DecodedFrame>, std::io::Error> {
+        if src.len() < Header::LENGTH {
+            return Ok(None);
+        }
+
+        let header = Header::deserialize(&mut &src[..Header::LENGTH])?;
+        let frame_length = Header::LENGTH + header.body_length as usize;
+        if frame_length > self.max_frame_size {
+            // Header is either corrupted or malicious, nothing after it can be trusted
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Frame of {} bytes exceeds the maximum frame size of {} bytes",
+                    frame_length, self.max_frame_size
+                ),
+            ));
+        }
+        if src.len() < frame_length {
+            // Make room for the rest of the frame, so it arrives in as few reads as possible
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+
+        let mut body = src.split_to(frame_length).freeze().slice(Header::LENGTH..);
+        if header.flags & Header::FLAG_COMPRESSION != 0 {
+            body = match self.compression {
+                Some(compression) => {
+                    Bytes::from(compression.decompress(&body, self.max_frame_size)?)
+                }
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Compressed frame received, but compression wasn't negotiated",
+                    ))
+                }
+            };
+        }
+        // Frame is already split off, so a body that can't be parsed leaves the stream in sync
+        let response = Response::deserialize(&header, body, self.protocol_version);
+
+        return Ok(Some((response, header.stream_id)));
+    }
+}
+
+// Response with the stream it answers, the response is an error when only its body is invalid
+pub type DecodedFrame = (Result<Response, std::io::Error>, StreamId);
+
+impl Default for ResponseCodec {
+    fn default() -> ResponseCodec {
+        return ResponseCodec::new(DEFAULT_MAX_FRAME_SIZE, None);
+    }
+}
+
+impl Decoder for ResponseCodec {
+    type Item = DecodedFrame;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if !self.segmented {
+            return self.decode_frame(src);
+        }
+
+        // Frames can span many segments, so they are decoded once their segments are unwrapped
+        let compression = segment_compression(self.compression)?;
+        while let Some(payload) = segment::decode_segment(src, compression)? {
+            self.frames.extend_from_slice(&payload);
+        }
+        let mut frames = std::mem::take(&mut self.frames);
+        let result = self.decode_frame(&mut frames);
+        self.frames = frames;
+        return result;
+    }
+}
+
+// Since v5 only compression that works on whole segments can be negotiated
+fn segment_compression(
+    compression: Option<Compression>,
+) -> Result<Option<SegmentCompression>, std::io::Error> {
+    let compression = match compression {
+        Some(compression) => compression,
+        None => return Ok(None),
+    };
+    match compression.for_segments() {
+        Some(segment_compression) => return Ok(Some(segment_compression)),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} compression can't be used for segments",
+                    compression.as_str()
+                ),
+            ))
+        }
+    }
+}
+
+pub type ResponseReader<T> = FramedRead<T, ResponseCodec>;
+
+pub fn response_reader<T: AsyncRead>(
+    reader: T,
+    max_frame_size: usize,
+    compression: Option<Compression>,
+) -> ResponseReader<T> {
+    return FramedRead::new(reader, ResponseCodec::new(max_frame_size, compression));
+}
+
+// Encodes requests into the write buffer, wrapping them in segments since v5
+// Frames are collected until finish, so that many of them share a segment
+pub struct RequestEncoder {
+    version: ProtocolVersion,
+    compression: Option<Compression>,
+    frames: BytesMut,
+}
+
+impl RequestEncoder {
+    pub fn new(version: ProtocolVersion, compression: Option<Compression>) -> RequestEncoder {
+        return RequestEncoder {
+            version,
+            compression,
+            frames: BytesMut::new(),
+        };
+    }
+
+    pub fn encode(&mut self, request: &Request, stream_id: StreamId, buf: &mut BytesMut) {
+        if self.version >= ProtocolVersion::V5 {
+            // Segments are compressed, not the frames inside them
+            request.serialize(stream_id, &mut self.frames, self.version, None);
+        } else {
+            request.serialize(stream_id, buf, self.version, self.compression);
+        }
+    }
+
+    // Appends segments holding the frames encoded since the last call, nothing before v5
+    // Compression that can't be used for segments is never negotiated, segments would be sent
+    // uncompressed and reading the responses fails
+    pub fn finish(&mut self, buf: &mut BytesMut) {
+        if !self.frames.is_empty() {
+            let compression = self
+                .compression
+                .and_then(|compression| compression.for_segments());
+            segment::encode_segments(&self.frames, buf, compression);
+            self.frames.clear();
+        }
+    }
+}
+
+// Waits for the next response, connection closed by the server is an error
+pub async fn read_response<T: AsyncRead + Unpin>(
+    reader: &mut ResponseReader<T>,
+) -> Result<(Response, StreamId), std::io::Error> {
+    let (response, stream_id) = read_response_frame(reader).await?;
+    return Ok((response?, stream_id));
+}
+
+// Like read_response, but only errors after which nothing more can be read are returned
+// as the outer error, invalid response bodies are returned with their stream id
+pub async fn read_response_frame<T: AsyncRead + Unpin>(
+    reader: &mut ResponseReader<T>,
+) -> Result<DecodedFrame, std::io::Error> {
+    match reader.next().await {
+        Some(result) => return result,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed by the server",
+            ))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ResultMessage;
+    use super::*;
+    use tokio_test::io::Builder;
+
+    const READY_FRAME: [u8; 9] = [0x84, 0, 0, 3, 2, 0, 0, 0, 0];
+    const VOID_RESULT_FRAME: [u8; 13] = [0x84, 0, 0, 4, 8, 0, 0, 0, 4, 0, 0, 0, 1];
+
+    #[test]
+    fn test_decode_waits_for_whole_frame() {
+        let mut buf = BytesMut::from(&VOID_RESULT_FRAME[..5]);
+        assert!(ResponseCodec::default().decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&VOID_RESULT_FRAME[5..12]);
+        assert!(ResponseCodec::default().decode(&mut buf).unwrap().is_none());
+        assert!(buf.capacity() >= VOID_RESULT_FRAME.len());
+
+        buf.extend_from_slice(&VOID_RESULT_FRAME[12..]);
+        let (response, stream_id) = ResponseCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(response.unwrap(), Response::Result(ResultMessage::Void));
+        assert_eq!(stream_id, 4);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_read_responses_from_one_read() {
+        let mut frames = READY_FRAME.to_vec();
+        frames.extend_from_slice(&VOID_RESULT_FRAME);
+
+        tokio_test::block_on(async {
+            let mock = Builder::new().read(&frames).build();
+            let mut reader = response_reader(mock, DEFAULT_MAX_FRAME_SIZE, None);
+
+            assert_eq!(
+                read_response(&mut reader).await.unwrap(),
+                (Response::Ready, 3)
+            );
+            let (_, stream_id) = read_response(&mut reader).await.unwrap();
+            assert_eq!(stream_id, 4);
+
+            let closed = read_response(&mut reader).await.unwrap_err();
+            assert_eq!(closed.kind(), std::io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    fn test_compressed_frame_needs_negotiated_compression() {
+        let mut frame = VOID_RESULT_FRAME;
+        frame[1] = Header::FLAG_COMPRESSION;
+
+        let mut buf = BytesMut::from(&frame[..]);
+        assert!(ResponseCodec::default().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "snappy")]
+    fn test_compressed_frame_decoding() {
+        let body = Compression::Snappy.compress(&VOID_RESULT_FRAME[Header::LENGTH..]);
+        let mut buf = BytesMut::from(&[0x84, Header::FLAG_COMPRESSION, 0, 4, 8][..]);
+        buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&body);
+
+        let mut codec = ResponseCodec::new(DEFAULT_MAX_FRAME_SIZE, Some(Compression::Snappy));
+        let (response, stream_id) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(response.unwrap(), Response::Result(ResultMessage::Void));
+        assert_eq!(stream_id, 4);
+    }
+
+    #[test]
+    #[cfg(feature = "snappy")]
+    fn test_snappy_segments_are_rejected() {
+        let mut codec = ResponseCodec::new(DEFAULT_MAX_FRAME_SIZE, Some(Compression::Snappy));
+        codec.enable_segments();
+        let mut buf = BytesMut::from(&[0u8; 16][..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        // Encoder doesn't fail, it falls back to uncompressed segments
+        let mut encoder = RequestEncoder::new(ProtocolVersion::V5, Some(Compression::Snappy));
+        let mut buf = BytesMut::new();
+        encoder.encode(&Request::Options, 1, &mut buf);
+        encoder.finish(&mut buf);
+        assert_eq!(buf[..3], [9, 0, 0x02]);
+    }
+
+    #[test]
+    fn test_response_version_must_match() {
+        let mut v3_frame = VOID_RESULT_FRAME;
+        v3_frame[0] = 0x83;
+
+        let mut buf = BytesMut::from(&v3_frame[..]);
+        let (response, _) = ResponseCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert!(response.is_err());
+
+        let mut codec = ResponseCodec::default();
+        codec.set_protocol_version(ProtocolVersion::V3);
+        let mut buf = BytesMut::from(&v3_frame[..]);
+        let (response, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(response.is_ok());
+    }
+
+    #[test]
+    fn test_invalid_body_fails_only_its_frame() {
+        // RESULT of an unknown kind, then a valid one
+        let mut buf = BytesMut::from(&[0x84, 0, 0, 2, 8, 0, 0, 0, 4, 0, 0, 0, 0x7f][..]);
+        buf.extend_from_slice(&VOID_RESULT_FRAME);
+
+        let mut codec = ResponseCodec::default();
+        let (response, stream_id) = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(response.is_err());
+        assert_eq!(stream_id, 2);
+        let (response, stream_id) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(response.unwrap(), Response::Result(ResultMessage::Void));
+        assert_eq!(stream_id, 4);
+    }
+
+    #[test]
+    fn test_oversized_frame_is_rejected() {
+        // Body length of 4 GiB - 1
+        let mut buf = BytesMut::from(&[0x84, 0, 0, 1, 8, 0xff, 0xff, 0xff, 0xff][..]);
+
+        let error = ResponseCodec::new(1024, None).decode(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(buf.capacity() < 1024);
+
+        let mut buf = BytesMut::from(&VOID_RESULT_FRAME[..]);
+        assert!(ResponseCodec::new(VOID_RESULT_FRAME.len() - 1, None)
+            .decode(&mut buf)
+            .is_err());
+        let mut buf = BytesMut::from(&VOID_RESULT_FRAME[..]);
+        assert!(ResponseCodec::new(VOID_RESULT_FRAME.len(), None)
+            .decode(&mut buf)
+            .unwrap()
+            .is_some());
+    }
+}
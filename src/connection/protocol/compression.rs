@@ -0,0 +1,180 @@
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 5)
+// Each algorithm is available only with its cargo feature enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "lz4")]
+    Lz4,
+    #[cfg(feature = "snappy")]
+    Snappy,
+}
+
+// Without any compression feature there are no variants and arguments go unused
+#[cfg_attr(not(any(feature = "lz4", feature = "snappy")), allow(unused_variables))]
+impl Compression {
+    // Value of COMPRESSION in STARTUP options
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => "lz4",
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => "snappy",
+        }
+    }
+
+    pub fn compress(&self, uncompressed: &[u8]) -> Vec<u8> {
+        match *self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                // [int] with uncompressed length followed by lz4 block
+                let compressed = lz4_flex::block::compress(uncompressed);
+                let mut body = Vec::with_capacity(4 + compressed.len());
+                body.extend_from_slice(&(uncompressed.len() as u32).to_be_bytes());
+                body.extend_from_slice(&compressed);
+                return body;
+            }
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => {
+                // Only fails when the input is larger than snappy can handle, around 4 GiB
+                return snap::raw::Encoder::new()
+                    .compress_vec(uncompressed)
+                    .expect("frame body too large for snappy");
+            }
+        }
+    }
+
+    // Uncompressed body larger than max_size is an error, so that a corrupted length
+    // can't make us allocate more than a frame can hold
+    pub fn decompress(
+        &self,
+        compressed: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        match *self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let mut buf = compressed;
+                let uncompressed_length = super::types::read_int(&mut buf)? as u32 as usize;
+                check_decompressed_size(uncompressed_length, max_size)?;
+                return lz4_flex::block::decompress(buf, uncompressed_length)
+                    .map_err(|error| make_decompress_error(&error.to_string()));
+            }
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => {
+                let uncompressed_length = snap::raw::decompress_len(compressed)
+                    .map_err(|error| make_decompress_error(&error.to_string()))?;
+                check_decompressed_size(uncompressed_length, max_size)?;
+                return snap::raw::Decoder::new()
+                    .decompress_vec(compressed)
+                    .map_err(|error| make_decompress_error(&error.to_string()));
+            }
+        }
+    }
+
+    // Since v5 whole segments are compressed instead of frames, only lz4 can do that
+    pub fn for_segments(&self) -> Option<SegmentCompression> {
+        match *self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => return Some(SegmentCompression::Lz4),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => return None,
+        }
+    }
+}
+
+// Compression of v5 segments, a separate type so that segments can't be given snappy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentCompression {
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+// Without lz4 there are no variants and arguments go unused
+#[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+impl SegmentCompression {
+    // Segment header holds the uncompressed length, so lz4 blocks aren't prefixed with it
+    pub fn compress(&self, uncompressed: &[u8]) -> Vec<u8> {
+        match *self {
+            #[cfg(feature = "lz4")]
+            SegmentCompression::Lz4 => return lz4_flex::block::compress(uncompressed),
+        }
+    }
+
+    pub fn decompress(
+        &self,
+        compressed: &[u8],
+        uncompressed_length: usize,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        match *self {
+            #[cfg(feature = "lz4")]
+            SegmentCompression::Lz4 => {
+                return lz4_flex::block::decompress(compressed, uncompressed_length)
+                    .map_err(|error| make_decompress_error(&error.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "lz4", feature = "snappy"))]
+fn check_decompressed_size(size: usize, max_size: usize) -> Result<(), std::io::Error> {
+    if size > max_size {
+        return Err(make_decompress_error(&format!(
+            "decompressed body of {} bytes exceeds the maximum frame size",
+            size
+        )));
+    }
+    return Ok(());
+}
+
+#[cfg(any(feature = "lz4", feature = "snappy"))]
+fn make_decompress_error(reason: &str) -> std::io::Error {
+    return std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Failed to decompress frame body: {}", reason),
+    );
+}
+
+#[cfg(test)]
+#[cfg(any(feature = "lz4", feature = "snappy"))]
+mod tests {
+    use super::*;
+
+    fn all_compressions() -> Vec<Compression> {
+        return vec![
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "snappy")]
+            Compression::Snappy,
+        ];
+    }
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let body: Vec<u8> = b"INSERT INTO ks.t (a, b) VALUES (?, ?)".repeat(100);
+
+        for compression in all_compressions() {
+            let compressed = compression.compress(&body);
+            assert!(compressed.len() < body.len());
+            assert_eq!(
+                compression.decompress(&compressed, body.len()).unwrap(),
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn test_decompressed_size_is_limited() {
+        let body = vec![0u8; 1024];
+
+        for compression in all_compressions() {
+            let compressed = compression.compress(&body);
+            assert!(compression.decompress(&compressed, 1023).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_lz4_body_starts_with_uncompressed_length() {
+        let compressed = Compression::Lz4.compress(&[7u8; 300]);
+        assert_eq!(compressed[..4], [0, 0, 1, 44]);
+    }
+}
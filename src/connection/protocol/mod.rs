@@ -1,28 +1,41 @@
+pub mod codec;
+pub mod compression;
 pub mod request;
 pub mod response;
+pub mod result;
+pub mod segment;
+pub mod serialize;
 pub mod types;
+pub mod value;
 
 pub use request::Request;
 pub use response::Response;
+pub use result::ResultMessage;
 pub use types::Header;
 pub use types::StreamId;
 
 #[cfg(test)]
 mod tests {
+    use super::codec::{read_response, response_reader, DEFAULT_MAX_FRAME_SIZE};
+    use super::types::ProtocolVersion;
     use super::*;
     use tokio::net::TcpStream;
 
     #[test]
     #[ignore]
     fn test_startup_scylla() {
-        let startup_request = Request::Startup;
+        let startup_request = Request::Startup(Default::default());
 
         tokio_test::block_on(async {
             let mut stream = TcpStream::connect("127.0.0.1:9042").await.unwrap();
 
             let stream_id: StreamId = 1;
-            startup_request.write(stream_id, &mut stream).await.unwrap();
-            let (resp, received_stream_id) = Response::read(&mut stream).await.unwrap();
+            startup_request
+                .write(stream_id, &mut stream, ProtocolVersion::V4, None)
+                .await
+                .unwrap();
+            let mut reader = response_reader(&mut stream, DEFAULT_MAX_FRAME_SIZE, None);
+            let (resp, received_stream_id) = read_response(&mut reader).await.unwrap();
             assert_eq!(resp, Response::Ready);
             assert_eq!(received_stream_id, stream_id);
         });
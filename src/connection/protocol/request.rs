@@ -1,12 +1,82 @@
+use super::compression::Compression;
+use super::serialize::SerializedValues;
+use super::types::{Consistency, ProtocolVersion, SerialConsistency};
 use super::Header;
 use super::StreamId;
-use bytes::BufMut;
-use std::collections::HashMap;
+use bytes::{BufMut, Bytes, BytesMut};
 use tokio::io::AsyncWriteExt;
 
 pub enum Request {
-    Startup,
+    Startup(StartupOptions),
+    // Asks which STARTUP options the server supports
+    Options,
+    Query(String, QueryParameters),
+    Prepare(String),
+    // Prepared statement id, id of its result metadata and parameters,
+    // result metadata id is sent only since v5
+    Execute(Vec<u8>, Vec<u8>, QueryParameters),
+    Batch(BatchParameters),
+    // SASL token answering AUTHENTICATE or AUTH_CHALLENGE
+    AuthResponse(Option<Vec<u8>>),
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.1.1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartupOptions {
+    pub cql_version: String,
+    // Compression to negotiate for all the following frames
+    pub compression: Option<Compression>,
+}
+
+impl Default for StartupOptions {
+    fn default() -> StartupOptions {
+        return StartupOptions {
+            cql_version: "3.0.0".to_string(),
+            compression: None,
+        };
+    }
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.1.4)
+#[derive(Default)]
+pub struct QueryParameters {
+    pub consistency: Consistency,
+    pub serial_consistency: Option<SerialConsistency>,
+    pub values: SerializedValues,
+    // Maximum number of rows in a single response, None means no paging
+    pub page_size: Option<i32>,
+    // Opaque state returned with the previous page, to fetch the next one
+    pub paging_state: Option<Bytes>,
+    // Keyspace used instead of the one of the connection, since v5
+    pub keyspace: Option<String>,
+    // Current time in seconds for the server to use, since v5
+    pub now_in_seconds: Option<i32>,
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.1.7)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchType {
+    Logged,
+    Unlogged,
+    Counter,
+}
+
+pub enum BatchQuery {
     Query(String),
+    // Prepared statement id
+    Prepared(Vec<u8>),
+}
+
+pub struct BatchParameters {
+    pub batch_type: BatchType,
+    pub queries: Vec<(BatchQuery, SerializedValues)>,
+    pub consistency: Consistency,
+    pub serial_consistency: Option<SerialConsistency>,
+    pub timestamp: Option<i64>,
+    // Keyspace used instead of the one of the connection, since v5
+    pub keyspace: Option<String>,
+    // Current time in seconds for the server to use, since v5
+    pub now_in_seconds: Option<i32>,
 }
 
 impl Request {
@@ -14,87 +84,390 @@ impl Request {
         &self,
         stream_id: StreamId,
         writer: &mut T,
+        version: ProtocolVersion,
+        compression: Option<Compression>,
     ) -> Result<(), std::io::Error> {
-        let body = self.body();
+        let mut buf = BytesMut::new();
+        self.serialize(stream_id, &mut buf, version, compression);
+        return writer.write_all(&buf).await;
+    }
+
+    // Appends the whole frame to buf, so many frames can be sent with a single write
+    // Body is written in place, its length is filled in the header afterwards
+    // STARTUP is never compressed, compression is only negotiated by it
+    pub fn serialize(
+        &self,
+        stream_id: StreamId,
+        buf: &mut BytesMut,
+        version: ProtocolVersion,
+        compression: Option<Compression>,
+    ) {
+        let frame_start = buf.len();
 
         let header = Header {
-            protocol_version: 0x04,
+            protocol_version: version.as_byte(),
             flags: 0,
             stream_id,
             opcode: self.opcode(),
-            body_length: body.len() as u32,
+            body_length: 0,
         };
+        header.write_to(buf);
+
+        let body_start = buf.len();
+        self.serialize_body(buf, version);
+
+        if let (Some(compression), false) = (compression, matches!(self, Self::Startup(_))) {
+            let compressed_body = compression.compress(&buf[body_start..]);
+            buf.truncate(body_start);
+            buf.extend_from_slice(&compressed_body);
+            buf[frame_start + 1] |= Header::FLAG_COMPRESSION;
+        }
 
-        header.serialize(writer).await?;
-        return writer.write_all(body.as_slice()).await;
+        let body_length = (buf.len() - body_start) as u32;
+        buf[frame_start + Header::LENGTH - 4..body_start]
+            .copy_from_slice(&body_length.to_be_bytes());
+    }
+
+    // Lowest protocol version that has everything the request uses
+    pub fn required_version(&self) -> ProtocolVersion {
+        let (keyspace, now_in_seconds, has_unset) = match self {
+            Self::Query(_, parameters) | Self::Execute(_, _, parameters) => (
+                &parameters.keyspace,
+                parameters.now_in_seconds,
+                parameters.values.has_unset(),
+            ),
+            Self::Batch(parameters) => (
+                &parameters.keyspace,
+                parameters.now_in_seconds,
+                parameters
+                    .queries
+                    .iter()
+                    .any(|(_, values)| values.has_unset()),
+            ),
+            _ => return ProtocolVersion::V3,
+        };
+        if keyspace.is_some() || now_in_seconds.is_some() {
+            return ProtocolVersion::V5;
+        }
+        if has_unset {
+            return ProtocolVersion::V4;
+        }
+        return ProtocolVersion::V3;
     }
 
     // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L166
     fn opcode(&self) -> u8 {
         match self {
-            Self::Startup => 0x01,
-            Self::Query(_) => 0x07,
+            Self::Startup(_) => 0x01,
+            Self::Options => 0x05,
+            Self::Query(..) => 0x07,
+            Self::Prepare(_) => 0x09,
+            Self::Execute(..) => 0x0A,
+            Self::Batch(_) => 0x0D,
+            Self::AuthResponse(_) => 0x0F,
         }
     }
 
-    // TODO get rid of vec allocation for every request
-    // e.g. split this to 2 funcitons, one for writing, second for size computation
-    fn body(&self) -> Vec<u8> {
+    fn serialize_body(&self, buf: &mut impl BufMut, version: ProtocolVersion) {
         match self {
-            Self::Startup => {
+            Self::Startup(options) => {
                 // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L269
-                let mut options: HashMap<String, String> = HashMap::new();
-                options.insert("CQL_VERSION".to_string(), "3.0.0".to_owned());
-                return serialize_map(options);
+                match options.compression {
+                    Some(compression) => serialize_map(
+                        &[
+                            ("CQL_VERSION", &options.cql_version),
+                            ("COMPRESSION", compression.as_str()),
+                        ],
+                        buf,
+                    ),
+                    None => serialize_map(&[("CQL_VERSION", &options.cql_version)], buf),
+                };
             }
-            Self::Query(q) => serialize_query(q),
+            // OPTIONS has an empty body
+            Self::Options => {}
+            Self::Query(q, parameters) => serialize_query(q, parameters, version, buf),
+            Self::Prepare(q) => serialize_prepare(q, version, buf),
+            Self::Execute(id, result_metadata_id, parameters) => {
+                serialize_execute(id, result_metadata_id, parameters, version, buf)
+            }
+            Self::Batch(parameters) => serialize_batch(parameters, version, buf),
+            // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.1.2)
+            Self::AuthResponse(token) => match token {
+                // [bytes]
+                Some(token) => {
+                    buf.put_i32(token.len() as i32);
+                    buf.put_slice(token);
+                }
+                None => buf.put_i32(-1),
+            },
         }
     }
 }
 
 // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L244
-fn serialize_map(map: HashMap<String, String>) -> Vec<u8> {
-    let mut buf = vec![];
-
+fn serialize_map(map: &[(&str, &str)], buf: &mut impl BufMut) {
     // [short] map length
     buf.put_u16(map.len() as u16);
     for (k, v) in map {
         // [short string] key
         buf.put_u16(k.len() as u16);
-        buf.put_slice(&k[..].as_bytes());
+        buf.put_slice(k.as_bytes());
 
         // [short string] value
         buf.put_u16(v.len() as u16);
-        buf.put_slice(&v[..].as_bytes());
+        buf.put_slice(v.as_bytes());
     }
-
-    return buf;
 }
 
 // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L309
-fn serialize_query(query: &str) -> Vec<u8> {
-    let mut buf = vec![];
+fn serialize_query(
+    query: &str,
+    parameters: &QueryParameters,
+    version: ProtocolVersion,
+    buf: &mut impl BufMut,
+) {
+    // [long string] with query
+    buf.put_u32(query.len() as u32);
+    buf.put_slice(query.as_bytes());
+
+    serialize_query_parameters(parameters, version, buf);
+}
 
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.1.5)
+fn serialize_prepare(query: &str, version: ProtocolVersion, buf: &mut impl BufMut) {
     // [long string] with query
     buf.put_u32(query.len() as u32);
     buf.put_slice(query.as_bytes());
 
-    // [consistency] ONE
-    buf.put_u16(0x0001);
+    if version >= ProtocolVersion::V5 {
+        // [int] flags, none of them is used
+        buf.put_u32(0);
+    }
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.1.6)
+fn serialize_execute(
+    id: &[u8],
+    result_metadata_id: &[u8],
+    parameters: &QueryParameters,
+    version: ProtocolVersion,
+    buf: &mut impl BufMut,
+) {
+    // [short bytes] with prepared statement id
+    buf.put_u16(id.len() as u16);
+    buf.put_slice(id);
+
+    if version >= ProtocolVersion::V5 {
+        // [short bytes] with result metadata id
+        buf.put_u16(result_metadata_id.len() as u16);
+        buf.put_slice(result_metadata_id);
+    }
+
+    serialize_query_parameters(parameters, version, buf);
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.1.7)
+fn serialize_batch(parameters: &BatchParameters, version: ProtocolVersion, buf: &mut impl BufMut) {
+    const WITH_SERIAL_CONSISTENCY: u32 = 0x10;
+    const WITH_DEFAULT_TIMESTAMP: u32 = 0x20;
+
+    // [byte] type
+    buf.put_u8(match parameters.batch_type {
+        BatchType::Logged => 0,
+        BatchType::Unlogged => 1,
+        BatchType::Counter => 2,
+    });
+
+    // [short] n with number of queries
+    buf.put_u16(parameters.queries.len() as u16);
+    for (query, values) in &parameters.queries {
+        match query {
+            BatchQuery::Query(q) => {
+                // [byte] kind followed by [long string] with query
+                buf.put_u8(0);
+                buf.put_u32(q.len() as u32);
+                buf.put_slice(q.as_bytes());
+            }
+            BatchQuery::Prepared(id) => {
+                // [byte] kind followed by [short bytes] with id
+                buf.put_u8(1);
+                buf.put_u16(id.len() as u16);
+                buf.put_slice(id);
+            }
+        };
+        values.write_to_request(buf);
+    }
+
+    // [consistency]
+    buf.put_u16(parameters.consistency.code());
+
+    let mut flags = 0;
+    if parameters.serial_consistency.is_some() {
+        flags |= WITH_SERIAL_CONSISTENCY;
+    }
+    if parameters.timestamp.is_some() {
+        flags |= WITH_DEFAULT_TIMESTAMP;
+    }
+    flags |= v5_flags(&parameters.keyspace, parameters.now_in_seconds, version);
+
+    serialize_flags(flags, version, buf);
+
+    if let Some(serial_consistency) = parameters.serial_consistency {
+        // [consistency]
+        buf.put_u16(Consistency::from(serial_consistency).code());
+    }
+
+    if let Some(timestamp) = parameters.timestamp {
+        // [long] timestamp in microseconds
+        buf.put_i64(timestamp);
+    }
+
+    serialize_v5_parameters(
+        &parameters.keyspace,
+        parameters.now_in_seconds,
+        version,
+        buf,
+    );
+}
+
+fn serialize_query_parameters(
+    parameters: &QueryParameters,
+    version: ProtocolVersion,
+    buf: &mut impl BufMut,
+) {
+    const VALUES: u32 = 0x01;
+    const PAGE_SIZE: u32 = 0x04;
+    const WITH_PAGING_STATE: u32 = 0x08;
+    const WITH_SERIAL_CONSISTENCY: u32 = 0x10;
+    const WITH_NAMES_FOR_VALUES: u32 = 0x40;
+
+    // [consistency]
+    buf.put_u16(parameters.consistency.code());
+
+    let mut flags = 0;
+    if !parameters.values.is_empty() {
+        flags |= VALUES;
+    }
+    if parameters.page_size.is_some() {
+        flags |= PAGE_SIZE;
+    }
+    if parameters.paging_state.is_some() {
+        flags |= WITH_PAGING_STATE;
+    }
+    if parameters.serial_consistency.is_some() {
+        flags |= WITH_SERIAL_CONSISTENCY;
+    }
+    if parameters.values.has_names() {
+        flags |= WITH_NAMES_FOR_VALUES;
+    }
+    flags |= v5_flags(&parameters.keyspace, parameters.now_in_seconds, version);
+
+    serialize_flags(flags, version, buf);
+
+    if !parameters.values.is_empty() {
+        parameters.values.write_to_request(buf);
+    }
+
+    if let Some(page_size) = parameters.page_size {
+        // [int] result_page_size
+        buf.put_i32(page_size);
+    }
+
+    if let Some(paging_state) = &parameters.paging_state {
+        // [bytes] paging_state
+        buf.put_i32(paging_state.len() as i32);
+        buf.put_slice(paging_state);
+    }
+
+    if let Some(serial_consistency) = parameters.serial_consistency {
+        // [consistency]
+        buf.put_u16(Consistency::from(serial_consistency).code());
+    }
+
+    serialize_v5_parameters(
+        &parameters.keyspace,
+        parameters.now_in_seconds,
+        version,
+        buf,
+    );
+}
+
+// [byte] flags, [int] since v5
+fn serialize_flags(flags: u32, version: ProtocolVersion, buf: &mut impl BufMut) {
+    if version >= ProtocolVersion::V5 {
+        buf.put_u32(flags);
+    } else {
+        buf.put_u8(flags as u8);
+    }
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v5.spec (section 4.1.4)
+// Older versions don't have these, connections refuse such requests before serializing them
+fn v5_flags(
+    keyspace: &Option<String>,
+    now_in_seconds: Option<i32>,
+    version: ProtocolVersion,
+) -> u32 {
+    const WITH_KEYSPACE: u32 = 0x80;
+    const WITH_NOW_IN_SECONDS: u32 = 0x100;
 
-    // [byte] flags - none
-    buf.put_u8(0);
+    let mut flags = 0;
+    if version < ProtocolVersion::V5 {
+        return flags;
+    }
+    if keyspace.is_some() {
+        flags |= WITH_KEYSPACE;
+    }
+    if now_in_seconds.is_some() {
+        flags |= WITH_NOW_IN_SECONDS;
+    }
+    return flags;
+}
+
+fn serialize_v5_parameters(
+    keyspace: &Option<String>,
+    now_in_seconds: Option<i32>,
+    version: ProtocolVersion,
+    buf: &mut impl BufMut,
+) {
+    if version < ProtocolVersion::V5 {
+        return;
+    }
 
-    return buf;
+    if let Some(keyspace) = keyspace {
+        // [string] keyspace
+        buf.put_u16(keyspace.len() as u16);
+        buf.put_slice(keyspace.as_bytes());
+    }
+
+    if let Some(now_in_seconds) = now_in_seconds {
+        // [int] now_in_seconds
+        buf.put_i32(now_in_seconds);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::serialize::Unset;
     use super::*;
     use tokio::io::AsyncReadExt;
     use tokio::net::TcpStream;
     use tokio_test::io::Builder;
 
+    fn body(request: &Request) -> Vec<u8> {
+        return versioned_body(request, ProtocolVersion::V4);
+    }
+
+    // Serializes a single frame and returns its body, checking the length in the header
+    fn versioned_body(request: &Request, version: ProtocolVersion) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        request.serialize(0, &mut buf, version, None);
+
+        let body_length = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
+        assert_eq!(body_length as usize, buf.len() - Header::LENGTH);
+        return buf[Header::LENGTH..].to_vec();
+    }
+
     #[test]
     fn test_startup_serialization() {
         let expected_startup = [
@@ -102,24 +475,241 @@ mod tests {
             95u8, 86u8, 69u8, 82u8, 83u8, 73u8, 79u8, 78u8, 0u8, 5u8, 51u8, 46u8, 48u8, 46u8, 48u8,
         ];
 
-        let req = Request::Startup;
+        let req = Request::Startup(Default::default());
         tokio_test::block_on(async {
             let mut mock = Builder::new().write(&expected_startup).build();
-            req.write(0, &mut mock).await.unwrap();
+            req.write(0, &mut mock, ProtocolVersion::V4, None)
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_startup_negotiates_compression() {
+        let mut buf = BytesMut::new();
+        let options = StartupOptions {
+            compression: Some(Compression::Lz4),
+            ..Default::default()
+        };
+        Request::Startup(options).serialize(
+            0,
+            &mut buf,
+            ProtocolVersion::V4,
+            Some(Compression::Lz4),
+        );
+
+        // STARTUP itself is sent uncompressed
+        assert_eq!(buf[1], 0);
+        let expected_body = [
+            0u8, 2, 0, 11, b'C', b'Q', b'L', b'_', b'V', b'E', b'R', b'S', b'I', b'O', b'N', 0, 5,
+            b'3', b'.', b'0', b'.', b'0', 0, 11, b'C', b'O', b'M', b'P', b'R', b'E', b'S', b'S',
+            b'I', b'O', b'N', 0, 3, b'l', b'z', b'4',
+        ];
+        assert_eq!(buf[Header::LENGTH..], expected_body[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_compressed_frame_serialization() {
+        let req = Request::Prepare("SELECT * FROM ks.t".repeat(10));
+        let mut buf = BytesMut::new();
+        req.serialize(3, &mut buf, ProtocolVersion::V4, Some(Compression::Lz4));
+
+        assert_eq!(buf[1], Header::FLAG_COMPRESSION);
+        let body_length = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
+        assert_eq!(body_length as usize, buf.len() - Header::LENGTH);
+
+        let decompressed = Compression::Lz4
+            .decompress(&buf[Header::LENGTH..], usize::MAX)
+            .unwrap();
+        assert_eq!(decompressed, body(&req));
+        assert!(buf.len() - Header::LENGTH < decompressed.len());
+    }
+
+    #[test]
+    fn test_options_serialization() {
+        let mut buf = BytesMut::new();
+        Request::Options.serialize(2, &mut buf, ProtocolVersion::V4, None);
+        assert_eq!(buf[..], [4u8, 0, 0, 2, 0x05, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_frames_appended_to_buffer() {
+        let mut buf = BytesMut::new();
+        Request::Prepare("q".to_string()).serialize(1, &mut buf, ProtocolVersion::V4, None);
+        Request::Prepare("ab".to_string()).serialize(2, &mut buf, ProtocolVersion::V4, None);
+
+        let expected_frames = [
+            4u8, 0, 0, 1, 0x09, 0, 0, 0, 5, 0, 0, 0, 1, b'q', // first frame
+            4, 0, 0, 2, 0x09, 0, 0, 0, 6, 0, 0, 0, 2, b'a', b'b', // second frame
+        ];
+        assert_eq!(buf[..], expected_frames[..]);
+    }
+
+    #[test]
+    fn test_query_with_values_serialization() {
+        let mut values = SerializedValues::new();
+        values.add_value(&7i32).unwrap();
+        values.add_value(&None::<i32>).unwrap();
+
+        let parameters = QueryParameters {
+            values,
+            ..Default::default()
+        };
+        let req = Request::Query("q".to_string(), parameters);
+        let expected_body = [
+            0u8, 0, 0, 1, b'q', 0, 1, 1, 0, 2, 0, 0, 0, 4, 0, 0, 0, 7, 0xff, 0xff, 0xff, 0xff,
+        ];
+        assert_eq!(body(&req), expected_body);
+    }
+
+    #[test]
+    fn test_query_with_named_values_serialization() {
+        let mut values = SerializedValues::new();
+        values.add_named_value("a", &7i32).unwrap();
+
+        let parameters = QueryParameters {
+            values,
+            ..Default::default()
+        };
+        let req = Request::Query("q".to_string(), parameters);
+        let expected_body = [
+            0u8, 0, 0, 1, b'q', 0, 1, 0x41, 0, 1, 0, 1, b'a', 0, 0, 0, 4, 0, 0, 0, 7,
+        ];
+        assert_eq!(body(&req), expected_body);
+    }
+
+    #[test]
+    fn test_query_consistency_serialization() {
+        let parameters = QueryParameters {
+            consistency: Consistency::LocalQuorum,
+            serial_consistency: Some(SerialConsistency::Serial),
+            ..Default::default()
+        };
+        let req = Request::Query("q".to_string(), parameters);
+        let expected_body = [0u8, 0, 0, 1, b'q', 0, 6, 0x10, 0, 8];
+        assert_eq!(body(&req), expected_body);
+    }
+
+    #[test]
+    fn test_query_paging_serialization() {
+        let parameters = QueryParameters {
+            page_size: Some(100),
+            paging_state: Some(Bytes::from_static(&[0xab, 0xcd])),
+            ..Default::default()
+        };
+        let req = Request::Query("q".to_string(), parameters);
+        let expected_body = [
+            0u8, 0, 0, 1, b'q', 0, 1, 0x0C, 0, 0, 0, 100, 0, 0, 0, 2, 0xab, 0xcd,
+        ];
+        assert_eq!(body(&req), expected_body);
+    }
+
+    #[test]
+    fn test_execute_serialization() {
+        let mut values = SerializedValues::new();
+        values.add_value(&1i8).unwrap();
+
+        let parameters = QueryParameters {
+            values,
+            ..Default::default()
+        };
+        let req = Request::Execute(vec![0xab, 0xcd], vec![0xef], parameters);
+        let expected_body = [0u8, 2, 0xab, 0xcd, 0, 1, 1, 0, 1, 0, 0, 0, 1, 1];
+        assert_eq!(body(&req), expected_body);
+    }
+
+    #[test]
+    fn test_v5_query_serialization() {
+        let parameters = QueryParameters {
+            keyspace: Some("ks".to_string()),
+            now_in_seconds: Some(7),
+            ..Default::default()
+        };
+        let req = Request::Query("q".to_string(), parameters);
+        assert_eq!(req.required_version(), ProtocolVersion::V5);
+
+        // [int] flags with keyspace and now_in_seconds, followed by both of them
+        let expected_body = [
+            0u8, 0, 0, 1, b'q', 0, 1, 0, 0, 0x01, 0x80, 0, 2, b'k', b's', 0, 0, 0, 7,
+        ];
+        assert_eq!(versioned_body(&req, ProtocolVersion::V5), expected_body);
+    }
+
+    #[test]
+    fn test_v5_execute_serialization() {
+        let req = Request::Execute(vec![0xab], vec![0xef], Default::default());
+        assert_eq!(req.required_version(), ProtocolVersion::V3);
+
+        let expected_body = [0u8, 1, 0xab, 0, 1, 0xef, 0, 1, 0, 0, 0, 0];
+        assert_eq!(versioned_body(&req, ProtocolVersion::V5), expected_body);
+    }
+
+    #[test]
+    fn test_unset_values_need_v4() {
+        let mut values = SerializedValues::new();
+        values.add_value(&Unset).unwrap();
+
+        let parameters = QueryParameters {
+            values: values.clone(),
+            ..Default::default()
+        };
+        let req = Request::Query("q".to_string(), parameters);
+        assert_eq!(req.required_version(), ProtocolVersion::V4);
+
+        let req = Request::Batch(BatchParameters {
+            batch_type: BatchType::Logged,
+            queries: vec![
+                (BatchQuery::Query("q".to_string()), SerializedValues::new()),
+                (BatchQuery::Query("q".to_string()), values),
+            ],
+            consistency: Consistency::One,
+            serial_consistency: None,
+            timestamp: None,
+            keyspace: None,
+            now_in_seconds: None,
+        });
+        assert_eq!(req.required_version(), ProtocolVersion::V4);
+    }
+
+    #[test]
+    fn test_batch_serialization() {
+        let mut values = SerializedValues::new();
+        values.add_value(&1i8).unwrap();
+
+        let req = Request::Batch(BatchParameters {
+            batch_type: BatchType::Unlogged,
+            queries: vec![
+                (BatchQuery::Query("q".to_string()), SerializedValues::new()),
+                (BatchQuery::Prepared(vec![0xab]), values),
+            ],
+            consistency: Consistency::Quorum,
+            serial_consistency: Some(SerialConsistency::LocalSerial),
+            timestamp: Some(3),
+            keyspace: None,
+            now_in_seconds: None,
         });
+        let expected_body = [
+            1u8, 0, 2, 0, 0, 0, 0, 1, b'q', 0, 0, 1, 0, 1, 0xab, 0, 1, 0, 0, 0, 1, 1, 0, 4, 0x30,
+            0, 9, 0, 0, 0, 0, 0, 0, 0, 3,
+        ];
+        assert_eq!(body(&req), expected_body);
     }
 
     #[test]
     #[ignore]
     fn test_startup_scylla_response() {
-        let req = Request::Startup;
+        let req = Request::Startup(Default::default());
         let expected_response = [0x84, 0, 0, 0, 2, 0, 0, 0, 0];
 
         tokio_test::block_on(async {
             let mut stream = TcpStream::connect("127.0.0.1:9042").await.unwrap();
-            req.write(0, &mut stream).await.unwrap();
+            req.write(0, &mut stream, ProtocolVersion::V4, None)
+                .await
+                .unwrap();
 
-            let mut response = expected_response.clone();
+            let mut response = expected_response;
             stream.read_exact(&mut response).await.unwrap();
 
             assert_eq!(expected_response, response);
@@ -1,13 +1,22 @@
+use super::result::ResultMessage;
+use super::types::{read_bytes_opt, read_int, read_string, read_string_multimap, ProtocolVersion};
 use super::Header;
-use super::StreamId;
-use bytes::Buf;
-use tokio::io::AsyncReadExt;
+use bytes::Bytes;
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq)]
 pub enum Response {
     Ready,
     Error(ErrorMessage),
-    Result,
+    Result(ResultMessage),
+    // STARTUP options and the values the server accepts for each of them
+    Supported(HashMap<String, Vec<String>>),
+    // Server requires authentication with the authenticator of the given class
+    Authenticate(String),
+    // SASL token the authenticator has to answer
+    AuthChallenge(Option<Vec<u8>>),
+    // Authentication is done, with the final SASL token
+    AuthSuccess(Option<Vec<u8>>),
 
     Invalid,
 }
@@ -18,27 +27,48 @@ pub struct ErrorMessage {
     code: u32,
 }
 
-impl Response {
-    pub async fn read<T: AsyncReadExt + Unpin>(
-        reader: &mut T,
-    ) -> Result<(Response, StreamId), std::io::Error> {
-        let header = Header::deserialize(reader).await?;
+impl ErrorMessage {
+    pub fn get_code(&self) -> u32 {
+        return self.code;
+    }
 
-        if header.protocol_version != 0x84 {
-            return Err(make_invalid_response_error());
-        }
+    pub fn get_message(&self) -> &str {
+        return &self.message;
+    }
+
+    // Prepared statement is not known by the server, e.g. after a restart
+    pub fn is_unprepared(&self) -> bool {
+        return self.code == 0x2500;
+    }
 
+    // Malformed request or one in a version the server doesn't speak
+    pub fn is_protocol_error(&self) -> bool {
+        return self.code == 0x000A;
+    }
+}
+
+impl Response {
+    // Body is a slice of the frame read from the connection, rows keep pointing into it
+    pub fn deserialize(
+        header: &Header,
+        body: Bytes,
+        version: ProtocolVersion,
+    ) -> Result<Response, std::io::Error> {
         let mut response = Response::from_opcode(header.opcode);
         if response == Response::Invalid {
             return Err(make_invalid_response_error());
         }
 
-        let mut body_buf = vec![0u8; header.body_length as usize];
+        // Server rejecting the version of a request answers with an error in a version
+        // it supports, error bodies are the same in all of them
+        let expected_version = version.as_byte() | Header::DIRECTION_RESPONSE;
+        if header.protocol_version != expected_version && !matches!(response, Self::Error(_)) {
+            return Err(make_invalid_response_error());
+        }
 
-        reader.read_exact(&mut body_buf).await?;
-        response.parse_body(body_buf.as_slice())?;
+        response.parse_body(body, version)?;
 
-        return Ok((response, header.stream_id));
+        return Ok(response);
     }
 
     // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L166
@@ -46,56 +76,111 @@ impl Response {
         match opcode {
             0x00 => Self::Error(Default::default()),
             0x02 => Self::Ready,
-            0x08 => Self::Result,
+            0x03 => Self::Authenticate(Default::default()),
+            0x06 => Self::Supported(Default::default()),
+            0x08 => Self::Result(ResultMessage::Void),
+            0x0E => Self::AuthChallenge(None),
+            0x10 => Self::AuthSuccess(None),
             _ => Self::Invalid,
         }
     }
 
-    fn parse_body(&mut self, mut body: &[u8]) -> Result<(), std::io::Error> {
+    fn parse_body(&mut self, body: Bytes, version: ProtocolVersion) -> Result<(), std::io::Error> {
         match self {
             // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L471
             Self::Error(error) => {
-                error.code = body.get_u32();
-
-                // [string] read string with error message
-                let string_len = body.get_u16();
-                let mut string_content = vec![0u8; string_len as usize];
+                let mut buf: &[u8] = &body;
+                error.code = read_int(&mut buf)? as u32;
                 // ignore additional info, that error body can have
-                if body.remaining() < string_len as usize {
-                    return Err(make_invalid_response_error());
-                }
-                body.copy_to_slice(&mut string_content);
-
-                error.message = String::from_utf8_lossy(string_content.as_slice()).to_string();
+                error.message = read_string(&mut buf)?;
                 return Ok(());
             }
             Self::Ready => Ok(()),
-            Self::Result => Ok(()),
+            Self::Result(result) => {
+                *result = ResultMessage::deserialize(body, version)?;
+                return Ok(());
+            }
+            // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L529
+            Self::Supported(options) => {
+                *options = read_string_multimap(&mut &body[..])?;
+                return Ok(());
+            }
+            // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.3)
+            Self::Authenticate(authenticator) => {
+                *authenticator = read_string(&mut &body[..])?;
+                return Ok(());
+            }
+            // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.7)
+            Self::AuthChallenge(token) | Self::AuthSuccess(token) => {
+                *token = read_bytes_opt(&mut &body[..])?.map(<[u8]>::to_vec);
+                return Ok(());
+            }
             Self::Invalid => Err(make_invalid_response_error()),
         }
     }
 }
 
 fn make_invalid_response_error() -> std::io::Error {
-    return std::io::Error::new(std::io::ErrorKind::Other, "Invalid response");
+    return std::io::Error::other("Invalid response");
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::codec::ResponseCodec;
     use super::*;
-    use tokio_test::io::Builder;
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
 
     #[test]
     fn test_startup_scylla_response() {
-        let ready_response = [0x84, 0, 0, 0, 2, 0, 0, 0, 0];
+        let mut ready_response = BytesMut::from(&[0x84, 0, 0, 0, 2, 0, 0, 0, 0][..]);
+
+        let (rsp, stream_id) = ResponseCodec::default()
+            .decode(&mut ready_response)
+            .unwrap()
+            .unwrap();
 
-        tokio_test::block_on(async {
-            let mut mock = Builder::new().read(&ready_response).build();
-            let (rsp, stream_id) = Response::read(&mut mock).await.unwrap();
+        assert_eq!(rsp.unwrap(), Response::Ready);
+        assert_eq!(stream_id, 0);
+    }
 
-            assert_eq!(rsp, Response::Ready);
-            assert_eq!(stream_id, 0);
-        });
+    #[test]
+    fn test_supported_response_reading() {
+        let mut supported_response = BytesMut::from(&[0x84, 0, 0, 1, 6, 0, 0, 0, 24][..]);
+        supported_response.extend_from_slice(&[
+            0, 1, 0, 11, b'C', b'Q', b'L', b'_', b'V', b'E', b'R', b'S', b'I', b'O', b'N', 0, 1, 0,
+            5, b'3', b'.', b'4', b'.', b'5',
+        ]);
+
+        let (rsp, _) = ResponseCodec::default()
+            .decode(&mut supported_response)
+            .unwrap()
+            .unwrap();
+
+        let mut expected_options = HashMap::new();
+        expected_options.insert("CQL_VERSION".to_string(), vec!["3.4.5".to_string()]);
+        assert_eq!(rsp.unwrap(), Response::Supported(expected_options));
+    }
+
+    #[test]
+    fn test_auth_responses_reading() {
+        let mut authenticate_response = BytesMut::from(&[0x84, 0, 0, 1, 3, 0, 0, 0, 5][..]);
+        authenticate_response.extend_from_slice(&[0, 3, b'A', b'u', b't']);
+        let mut challenge_response = BytesMut::from(&[0x84, 0, 0, 1, 0x0E, 0, 0, 0, 6][..]);
+        challenge_response.extend_from_slice(&[0, 0, 0, 2, 0xab, 0xcd]);
+        let mut success_response = BytesMut::from(&[0x84, 0, 0, 1, 0x10, 0, 0, 0, 4][..]);
+        success_response.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+
+        let mut codec = ResponseCodec::default();
+        let (rsp, _) = codec.decode(&mut authenticate_response).unwrap().unwrap();
+        assert_eq!(rsp.unwrap(), Response::Authenticate("Aut".to_string()));
+        let (rsp, _) = codec.decode(&mut challenge_response).unwrap().unwrap();
+        assert_eq!(
+            rsp.unwrap(),
+            Response::AuthChallenge(Some(vec![0xab, 0xcd]))
+        );
+        let (rsp, _) = codec.decode(&mut success_response).unwrap().unwrap();
+        assert_eq!(rsp.unwrap(), Response::AuthSuccess(None));
     }
 
     #[test]
@@ -108,18 +193,16 @@ mod tests {
             0x73, 0x61, 0x64, 0x73, 0x64, 0x61, 0x73, 0x64, 0x27,
         ];
 
-        tokio_test::block_on(async {
-            let mut mock = Builder::new().read(&error_response).build();
-            let (rsp, stream_id) = Response::read(&mut mock).await.unwrap();
-
-            assert_eq!(
-                rsp,
-                Response::Error(ErrorMessage {
-                    code: 8192,
-                    message: String::from("line 1:0 no viable alternative at input \'sadsdasd\'"),
-                })
-            );
-            assert_eq!(stream_id, 32766);
-        });
+        let mut buf = BytesMut::from(&error_response[..]);
+        let (rsp, stream_id) = ResponseCodec::default().decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(
+            rsp.unwrap(),
+            Response::Error(ErrorMessage {
+                code: 8192,
+                message: String::from("line 1:0 no viable alternative at input \'sadsdasd\'"),
+            })
+        );
+        assert_eq!(stream_id, 32766);
     }
 }
@@ -0,0 +1,603 @@
+use super::types::{
+    make_parse_error, read_bytes_opt, read_int, read_short, read_short_bytes, read_string,
+    read_string_list, ColumnType, ProtocolVersion,
+};
+use super::value::{CqlValue, ParseError};
+use bytes::Bytes;
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.5)
+#[derive(Debug, PartialEq)]
+pub enum ResultMessage {
+    Void,
+    Rows(Rows),
+    SetKeyspace(SetKeyspace),
+    Prepared(Prepared),
+    SchemaChange(SchemaChange),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Rows {
+    pub metadata: ResultMetadata,
+    pub rows_count: usize,
+    // Empty when there are no columns, such rows are only counted
+    pub rows: Vec<Row>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultMetadata {
+    pub col_count: usize,
+    // Set when the result is not complete and more pages can be fetched
+    // Copied out of the frame, so paging doesn't keep the whole response alive
+    pub paging_state: Option<Bytes>,
+    // Set since v5 when the metadata differs from the one the statement was prepared with
+    pub new_metadata_id: Option<Vec<u8>>,
+    // Empty if the server was asked to skip the metadata
+    pub col_specs: Vec<ColumnSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnSpec {
+    pub keyspace: String,
+    pub table: String,
+    pub name: String,
+    pub typ: ColumnType,
+}
+
+// Row holds raw serialized cells, None means null
+// Cells are slices of the response frame, they aren't copied out of it
+#[derive(Debug, PartialEq)]
+pub struct Row {
+    pub columns: Vec<Option<Bytes>>,
+}
+
+impl Row {
+    pub fn get_raw(&self, column_index: usize) -> Option<&[u8]> {
+        return self.columns.get(column_index)?.as_deref();
+    }
+
+    // Decodes the cell at column_index, panics if the index is out of bounds
+    pub fn get_value(
+        &self,
+        column_index: usize,
+        typ: &ColumnType,
+    ) -> Result<Option<CqlValue>, ParseError> {
+        return CqlValue::deserialize_cell(typ, self.columns[column_index].as_deref());
+    }
+}
+
+// Outcome of a successful query as returned to the user
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryResult {
+    // Present only if the query returned rows, e.g. a SELECT
+    pub rows: Option<Rows>,
+}
+
+impl QueryResult {
+    // Opaque state to pass when fetching the next page, None if this was the last page
+    pub fn paging_state(&self) -> Option<&[u8]> {
+        return self.rows.as_ref()?.metadata.paging_state.as_deref();
+    }
+}
+
+impl From<ResultMessage> for QueryResult {
+    fn from(result: ResultMessage) -> QueryResult {
+        return match result {
+            ResultMessage::Rows(rows) => QueryResult { rows: Some(rows) },
+            _ => QueryResult { rows: None },
+        };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SetKeyspace {
+    pub keyspace_name: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Prepared {
+    pub id: Vec<u8>,
+    // Sent since v5, empty before
+    pub result_metadata_id: Vec<u8>,
+    pub prepared_metadata: PreparedMetadata,
+    pub result_metadata: ResultMetadata,
+}
+
+// Describes values that have to be bound to the prepared statement
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreparedMetadata {
+    pub col_count: usize,
+    // Indexes of bound values that make up the partition key
+    pub pk_indexes: Vec<u16>,
+    pub col_specs: Vec<ColumnSpec>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SchemaChange {
+    pub change_type: SchemaChangeType,
+    pub target: SchemaChangeTarget,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaChangeType {
+    Created,
+    Updated,
+    Dropped,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaChangeTarget {
+    Keyspace {
+        keyspace: String,
+    },
+    Table {
+        keyspace: String,
+        table: String,
+    },
+    Type {
+        keyspace: String,
+        type_name: String,
+    },
+    Function {
+        keyspace: String,
+        function_name: String,
+        arguments: Vec<String>,
+    },
+    Aggregate {
+        keyspace: String,
+        aggregate_name: String,
+        arguments: Vec<String>,
+    },
+}
+
+impl ResultMessage {
+    pub fn deserialize(
+        body: Bytes,
+        version: ProtocolVersion,
+    ) -> Result<ResultMessage, std::io::Error> {
+        let mut body_slice: &[u8] = &body;
+        let buf = &mut body_slice;
+
+        // [int] kind
+        return match read_int(buf)? {
+            0x0001 => Ok(Self::Void),
+            0x0002 => Ok(Self::Rows(Rows::deserialize(&body, buf)?)),
+            0x0003 => Ok(Self::SetKeyspace(SetKeyspace {
+                keyspace_name: read_string(buf)?,
+            })),
+            0x0004 => Ok(Self::Prepared(Prepared {
+                id: read_short_bytes(buf)?,
+                result_metadata_id: match version {
+                    ProtocolVersion::V3 | ProtocolVersion::V4 => vec![],
+                    ProtocolVersion::V5 => read_short_bytes(buf)?,
+                },
+                prepared_metadata: PreparedMetadata::deserialize(buf, version)?,
+                result_metadata: ResultMetadata::deserialize(buf)?,
+            })),
+            0x0005 => Ok(Self::SchemaChange(SchemaChange::deserialize(buf)?)),
+            _ => Err(make_parse_error("unknown result kind")),
+        };
+    }
+}
+
+impl ResultMetadata {
+    // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.5.2)
+    fn deserialize(buf: &mut &[u8]) -> Result<ResultMetadata, std::io::Error> {
+        const GLOBAL_TABLES_SPEC: i32 = 0x0001;
+        const HAS_MORE_PAGES: i32 = 0x0002;
+        const NO_METADATA: i32 = 0x0004;
+        const METADATA_CHANGED: i32 = 0x0008;
+
+        let flags = read_int(buf)?;
+        let col_count = read_count(buf)?;
+
+        let mut paging_state = None;
+        if flags & HAS_MORE_PAGES != 0 {
+            paging_state = read_bytes_opt(buf)?.map(Bytes::copy_from_slice);
+        }
+
+        let mut new_metadata_id = None;
+        if flags & METADATA_CHANGED != 0 {
+            new_metadata_id = Some(read_short_bytes(buf)?);
+        }
+
+        if flags & NO_METADATA != 0 {
+            return Ok(ResultMetadata {
+                col_count,
+                paging_state,
+                new_metadata_id,
+                col_specs: vec![],
+            });
+        }
+
+        let col_specs = read_col_specs(buf, flags & GLOBAL_TABLES_SPEC != 0, col_count)?;
+
+        return Ok(ResultMetadata {
+            col_count,
+            paging_state,
+            new_metadata_id,
+            col_specs,
+        });
+    }
+}
+
+impl PreparedMetadata {
+    // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.5.4)
+    fn deserialize(
+        buf: &mut &[u8],
+        version: ProtocolVersion,
+    ) -> Result<PreparedMetadata, std::io::Error> {
+        const GLOBAL_TABLES_SPEC: i32 = 0x0001;
+
+        let flags = read_int(buf)?;
+        let col_count = read_count(buf)?;
+
+        // Partition key indexes were added in v4
+        let mut pk_indexes = vec![];
+        if version >= ProtocolVersion::V4 {
+            let pk_count = read_count(buf)?;
+            pk_indexes.reserve(pk_count.min(buf.len()));
+            for _ in 0..pk_count {
+                pk_indexes.push(read_short(buf)?);
+            }
+        }
+
+        let col_specs = read_col_specs(buf, flags & GLOBAL_TABLES_SPEC != 0, col_count)?;
+
+        return Ok(PreparedMetadata {
+            col_count,
+            pk_indexes,
+            col_specs,
+        });
+    }
+}
+
+// Column specs, with a single table spec up front if global_tables_spec is set
+fn read_col_specs(
+    buf: &mut &[u8],
+    global_tables_spec: bool,
+    col_count: usize,
+) -> Result<Vec<ColumnSpec>, std::io::Error> {
+    let mut global_table_spec = None;
+    if global_tables_spec {
+        global_table_spec = Some((read_string(buf)?, read_string(buf)?));
+    }
+
+    let mut col_specs = Vec::with_capacity(col_count.min(buf.len()));
+    for _ in 0..col_count {
+        let (keyspace, table) = match &global_table_spec {
+            Some(table_spec) => table_spec.clone(),
+            None => (read_string(buf)?, read_string(buf)?),
+        };
+        col_specs.push(ColumnSpec {
+            keyspace,
+            table,
+            name: read_string(buf)?,
+            typ: ColumnType::deserialize(buf)?,
+        });
+    }
+    return Ok(col_specs);
+}
+
+impl Rows {
+    // Decodes all cells of the row using types from result metadata
+    pub fn typed_row(&self, row_index: usize) -> Result<Vec<Option<CqlValue>>, ParseError> {
+        let row = &self.rows[row_index];
+        if self.metadata.col_specs.len() != row.columns.len() {
+            return Err(ParseError::BadData("result has no column metadata".into()));
+        }
+
+        let mut values = Vec::with_capacity(row.columns.len());
+        for (column_index, spec) in self.metadata.col_specs.iter().enumerate() {
+            values.push(row.get_value(column_index, &spec.typ)?);
+        }
+        return Ok(values);
+    }
+
+    // buf is the remaining part of body
+    fn deserialize(body: &Bytes, buf: &mut &[u8]) -> Result<Rows, std::io::Error> {
+        let metadata = ResultMetadata::deserialize(buf)?;
+        let rows_count = read_count(buf)?;
+
+        // Every cell takes at least 4 bytes, so a count the body can't hold is rejected
+        // before allocating anything for it
+        if metadata.col_count == 0 {
+            return Ok(Rows {
+                metadata,
+                rows_count,
+                rows: Vec::new(),
+            });
+        }
+        let min_cells_length = rows_count
+            .checked_mul(metadata.col_count)
+            .and_then(|cells_count| cells_count.checked_mul(4));
+        if min_cells_length.is_none_or(|length| length > buf.len()) {
+            return Err(make_parse_error("rows count exceeds the frame body"));
+        }
+
+        let mut rows = Vec::with_capacity(rows_count);
+        for _ in 0..rows_count {
+            let mut columns = Vec::with_capacity(metadata.col_count.min(buf.len()));
+            for _ in 0..metadata.col_count {
+                columns.push(read_bytes_opt(buf)?.map(|cell| body.slice_ref(cell)));
+            }
+            rows.push(Row { columns });
+        }
+
+        return Ok(Rows {
+            metadata,
+            rows_count,
+            rows,
+        });
+    }
+}
+
+// [int] used as a count must not be negative
+fn read_count(buf: &mut &[u8]) -> Result<usize, std::io::Error> {
+    let count = read_int(buf)?;
+    if count < 0 {
+        return Err(make_parse_error("negative count"));
+    }
+    return Ok(count as usize);
+}
+
+impl SchemaChange {
+    // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.5.5)
+    fn deserialize(buf: &mut &[u8]) -> Result<SchemaChange, std::io::Error> {
+        let change_type = match read_string(buf)?.as_str() {
+            "CREATED" => SchemaChangeType::Created,
+            "UPDATED" => SchemaChangeType::Updated,
+            "DROPPED" => SchemaChangeType::Dropped,
+            _ => return Err(make_parse_error("unknown schema change type")),
+        };
+
+        let target = match read_string(buf)?.as_str() {
+            "KEYSPACE" => SchemaChangeTarget::Keyspace {
+                keyspace: read_string(buf)?,
+            },
+            "TABLE" => SchemaChangeTarget::Table {
+                keyspace: read_string(buf)?,
+                table: read_string(buf)?,
+            },
+            "TYPE" => SchemaChangeTarget::Type {
+                keyspace: read_string(buf)?,
+                type_name: read_string(buf)?,
+            },
+            "FUNCTION" => SchemaChangeTarget::Function {
+                keyspace: read_string(buf)?,
+                function_name: read_string(buf)?,
+                arguments: read_string_list(buf)?,
+            },
+            "AGGREGATE" => SchemaChangeTarget::Aggregate {
+                keyspace: read_string(buf)?,
+                aggregate_name: read_string(buf)?,
+                arguments: read_string_list(buf)?,
+            },
+            _ => return Err(make_parse_error("unknown schema change target")),
+        };
+
+        return Ok(SchemaChange {
+            change_type,
+            target,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deserialize(body: &[u8]) -> Result<ResultMessage, std::io::Error> {
+        return ResultMessage::deserialize(Bytes::copy_from_slice(body), ProtocolVersion::V4);
+    }
+
+    #[test]
+    fn test_void_result() {
+        let body = [0, 0, 0, 1];
+        assert_eq!(deserialize(&body).unwrap(), ResultMessage::Void);
+    }
+
+    #[test]
+    fn test_set_keyspace_result() {
+        let body = [0, 0, 0, 3, 0, 2, b'k', b's'];
+        assert_eq!(
+            deserialize(&body).unwrap(),
+            ResultMessage::SetKeyspace(SetKeyspace {
+                keyspace_name: "ks".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_schema_change_result() {
+        let mut body = vec![0, 0, 0, 5];
+        for s in &["CREATED", "TABLE", "ks", "t"] {
+            body.extend_from_slice(&(s.len() as u16).to_be_bytes());
+            body.extend_from_slice(s.as_bytes());
+        }
+
+        assert_eq!(
+            deserialize(&body).unwrap(),
+            ResultMessage::SchemaChange(SchemaChange {
+                change_type: SchemaChangeType::Created,
+                target: SchemaChangeTarget::Table {
+                    keyspace: "ks".to_string(),
+                    table: "t".to_string(),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_rows_result() {
+        let mut body = vec![0, 0, 0, 2];
+        // flags: global_tables_spec, columns_count: 2
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        body.extend_from_slice(&[0, 2, b'k', b's', 0, 1, b't']);
+        // a int, b varchar
+        body.extend_from_slice(&[0, 1, b'a', 0, 0x09, 0, 1, b'b', 0, 0x0D]);
+        // rows_count: 2
+        body.extend_from_slice(&[0, 0, 0, 2]);
+        body.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 7, 0, 0, 0, 1, b'x']);
+        body.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 8, 0xff, 0xff, 0xff, 0xff]);
+        let body = Bytes::from(body);
+
+        let rows = match ResultMessage::deserialize(body.clone(), ProtocolVersion::V4).unwrap() {
+            ResultMessage::Rows(rows) => rows,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+
+        assert_eq!(rows.metadata.col_count, 2);
+        assert_eq!(rows.metadata.paging_state, None);
+        assert_eq!(
+            rows.metadata.col_specs[1],
+            ColumnSpec {
+                keyspace: "ks".to_string(),
+                table: "t".to_string(),
+                name: "b".to_string(),
+                typ: ColumnType::Text,
+            }
+        );
+        assert_eq!(rows.rows_count, 2);
+        assert_eq!(rows.rows[0].get_raw(1), Some(&b"x"[..]));
+        assert_eq!(rows.rows[1].get_raw(0), Some(&[0, 0, 0, 8][..]));
+        assert_eq!(rows.rows[1].get_raw(1), None);
+        // Cells point into the body instead of being copied out of it
+        let cell = rows.rows[0].columns[1].as_ref().unwrap();
+        assert!(body.as_ptr_range().contains(&cell.as_ptr()));
+        assert_eq!(
+            rows.typed_row(0).unwrap(),
+            vec![
+                Some(CqlValue::Int(7)),
+                Some(CqlValue::Text("x".to_string()))
+            ]
+        );
+        assert_eq!(
+            rows.typed_row(1).unwrap(),
+            vec![Some(CqlValue::Int(8)), None]
+        );
+    }
+
+    #[test]
+    fn test_rows_result_no_metadata_with_paging_state() {
+        let mut body = vec![0, 0, 0, 2];
+        // flags: has_more_pages | no_metadata, columns_count: 1
+        body.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1]);
+        body.extend_from_slice(&[0, 0, 0, 2, 0xab, 0xcd]);
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 0x2a]);
+
+        let rows = match deserialize(&body).unwrap() {
+            ResultMessage::Rows(rows) => rows,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+
+        assert_eq!(rows.metadata.paging_state.unwrap(), [0xab, 0xcd][..]);
+        assert!(rows.metadata.col_specs.is_empty());
+        assert_eq!(rows.rows[0].get_raw(0), Some(&[0x2a][..]));
+    }
+
+    #[test]
+    fn test_prepared_result() {
+        let mut body = vec![0, 0, 0, 4, 0, 2, 0xde, 0xad];
+        // prepared metadata: global_tables_spec, 2 columns, 1 pk index
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0]);
+        body.extend_from_slice(&[0, 2, b'k', b's', 0, 1, b't']);
+        body.extend_from_slice(&[0, 1, b'a', 0, 0x09, 0, 1, b'b', 0, 0x0D]);
+        // result metadata: no_metadata, 0 columns
+        body.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0]);
+
+        let prepared = match deserialize(&body).unwrap() {
+            ResultMessage::Prepared(prepared) => prepared,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+
+        assert_eq!(prepared.id, vec![0xde, 0xad]);
+        assert_eq!(prepared.prepared_metadata.col_count, 2);
+        assert_eq!(prepared.prepared_metadata.pk_indexes, vec![0]);
+        assert_eq!(prepared.prepared_metadata.col_specs[0].name, "a");
+        assert_eq!(
+            prepared.prepared_metadata.col_specs[1].typ,
+            ColumnType::Text
+        );
+        assert_eq!(prepared.result_metadata, ResultMetadata::default());
+    }
+
+    #[test]
+    fn test_v3_prepared_result_has_no_pk_indexes() {
+        let mut body = vec![0, 0, 0, 4, 0, 1, 0xab];
+        // prepared metadata: global_tables_spec, 1 column
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]);
+        body.extend_from_slice(&[0, 2, b'k', b's', 0, 1, b't', 0, 1, b'a', 0, 0x09]);
+        body.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0]);
+
+        let body = Bytes::copy_from_slice(&body);
+        let prepared = match ResultMessage::deserialize(body, ProtocolVersion::V3).unwrap() {
+            ResultMessage::Prepared(prepared) => prepared,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+
+        assert!(prepared.prepared_metadata.pk_indexes.is_empty());
+        assert_eq!(prepared.prepared_metadata.col_specs[0].typ, ColumnType::Int);
+    }
+
+    #[test]
+    fn test_v5_prepared_result_has_result_metadata_id() {
+        let mut body = vec![0, 0, 0, 4, 0, 1, 0xab, 0, 2, 0xcd, 0xef];
+        // prepared metadata: no columns, no pk indexes
+        body.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // result metadata: no_metadata and metadata_changed, 1 column
+        body.extend_from_slice(&[0, 0, 0, 0x0C, 0, 0, 0, 1, 0, 1, 0x42]);
+
+        let body = Bytes::copy_from_slice(&body);
+        let prepared = match ResultMessage::deserialize(body, ProtocolVersion::V5).unwrap() {
+            ResultMessage::Prepared(prepared) => prepared,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+
+        assert_eq!(prepared.id, vec![0xab]);
+        assert_eq!(prepared.result_metadata_id, vec![0xcd, 0xef]);
+        assert_eq!(prepared.result_metadata.new_metadata_id, Some(vec![0x42]));
+    }
+
+    #[test]
+    fn test_rows_count_is_bounded_by_body() {
+        // No columns and 2^31 - 1 rows, only the count is kept
+        let mut body = vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0];
+        body.extend_from_slice(&[0x7f, 0xff, 0xff, 0xff]);
+        let rows = match deserialize(&body).unwrap() {
+            ResultMessage::Rows(rows) => rows,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        assert_eq!(rows.rows_count, i32::MAX as usize);
+        assert!(rows.rows.is_empty());
+
+        // Columns and more rows than cells in the body
+        let mut body = vec![0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1];
+        body.extend_from_slice(&[0, 2, b'k', b's', 0, 1, b't', 0, 1, b'a', 0, 0x09]);
+        body.extend_from_slice(&[0x7f, 0xff, 0xff, 0xff, 0, 0, 0, 4, 0, 0, 0, 7]);
+        assert!(deserialize(&body).is_err());
+    }
+
+    #[test]
+    fn test_column_type_nesting_is_limited() {
+        // Rows metadata with one column, whose type is lists nested depth times in an int
+        let rows_body = |depth: usize| {
+            let mut body = vec![0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1];
+            body.extend_from_slice(&[0, 2, b'k', b's', 0, 1, b't', 0, 1, b'a']);
+            for _ in 0..depth {
+                body.extend_from_slice(&[0, 0x20]);
+            }
+            body.extend_from_slice(&[0, 0x09, 0, 0, 0, 0]);
+            return body;
+        };
+
+        assert!(deserialize(&rows_body(64)).is_ok());
+        assert!(deserialize(&rows_body(65)).is_err());
+        assert!(deserialize(&rows_body(100_000)).is_err());
+    }
+
+    #[test]
+    fn test_truncated_result() {
+        let body = [0, 0, 0, 3, 0, 5, b'k'];
+        assert!(deserialize(&body).is_err());
+    }
+}
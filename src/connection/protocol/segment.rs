@@ -0,0 +1,273 @@
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v5.spec (section 2)
+// Since v5 frames sent after the handshake are wrapped in segments, each with a checksum
+// of its header and payload. Self-contained segments hold whole frames, frames larger
+// than a segment are split into many segments that aren't self-contained.
+
+use super::compression::SegmentCompression;
+use super::Header;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub const MAX_PAYLOAD_LENGTH: usize = 0x1FFFF;
+
+const CRC24_INIT: u32 = 0x875060;
+const CRC24_POLY: u32 = 0x1974F0B;
+const CRC32_INITIAL_BYTES: [u8; 4] = [0xFA, 0x2D, 0x55, 0xCA];
+
+const CRC24_LENGTH: usize = 3;
+const CRC32_LENGTH: usize = 4;
+
+// Checksum of the segment header, computed over its little endian bytes
+pub fn crc24(bytes: &[u8]) -> u32 {
+    let mut crc = CRC24_INIT;
+    for byte in bytes {
+        crc ^= (*byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    return crc;
+}
+
+// Checksum of the segment payload, CRC32 seeded with 4 fixed bytes
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&CRC32_INITIAL_BYTES);
+    hasher.update(bytes);
+    return hasher.finalize();
+}
+
+// Header is 3 bytes without compression, 5 with it, both followed by their CRC24
+fn header_length(compression: Option<SegmentCompression>) -> usize {
+    return match compression {
+        Some(_) => 5,
+        None => 3,
+    };
+}
+
+// Appends segments holding all the frames, which have to be complete
+// Frames are packed into self-contained segments, one that doesn't fit alone
+// is split across as many segments as it needs
+pub fn encode_segments(frames: &[u8], buf: &mut BytesMut, compression: Option<SegmentCompression>) {
+    let mut rest = frames;
+    while !rest.is_empty() {
+        let mut length = 0;
+        while length < rest.len() {
+            let frame_length = frame_length(&rest[length..]);
+            if length + frame_length > MAX_PAYLOAD_LENGTH {
+                break;
+            }
+            length += frame_length;
+        }
+
+        if length > 0 {
+            write_segment(&rest[..length], true, buf, compression);
+            rest = &rest[length..];
+            continue;
+        }
+
+        let frame_length = frame_length(rest);
+        for part in rest[..frame_length].chunks(MAX_PAYLOAD_LENGTH) {
+            write_segment(part, false, buf, compression);
+        }
+        rest = &rest[frame_length..];
+    }
+}
+
+fn frame_length(frame: &[u8]) -> usize {
+    let body_length = u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]);
+    return Header::LENGTH + body_length as usize;
+}
+
+fn write_segment(
+    payload: &[u8],
+    self_contained: bool,
+    buf: &mut BytesMut,
+    compression: Option<SegmentCompression>,
+) {
+    let self_contained = self_contained as u64;
+
+    // Payload that doesn't get smaller is sent as it is, with uncompressed length of 0
+    let compressed = compression
+        .map(|compression| compression.compress(payload))
+        .filter(|compressed| compressed.len() < payload.len());
+    let (header, payload) = match (compression, &compressed) {
+        (None, _) => (payload.len() as u64 | self_contained << 17, payload),
+        (Some(_), Some(compressed)) => (
+            compressed.len() as u64 | (payload.len() as u64) << 17 | self_contained << 34,
+            &compressed[..],
+        ),
+        (Some(_), None) => (payload.len() as u64 | self_contained << 34, payload),
+    };
+
+    let header = &header.to_le_bytes()[..header_length(compression)];
+    buf.put_slice(header);
+    buf.put_slice(&crc24(header).to_le_bytes()[..CRC24_LENGTH]);
+    buf.put_slice(payload);
+    buf.put_u32_le(crc32(payload));
+}
+
+// Takes the next segment out of src and returns its uncompressed payload,
+// None until the whole segment arrives
+pub fn decode_segment(
+    src: &mut BytesMut,
+    compression: Option<SegmentCompression>,
+) -> Result<Option<Bytes>, std::io::Error> {
+    let header_length = header_length(compression);
+    if src.len() < header_length + CRC24_LENGTH {
+        return Ok(None);
+    }
+
+    let mut header_bytes = [0u8; 8];
+    header_bytes[..header_length].copy_from_slice(&src[..header_length]);
+    let mut header_crc_bytes = [0u8; 4];
+    header_crc_bytes[..CRC24_LENGTH]
+        .copy_from_slice(&src[header_length..header_length + CRC24_LENGTH]);
+    if crc24(&src[..header_length]) != u32::from_le_bytes(header_crc_bytes) {
+        // Length can't be trusted either, so nothing after this can be read
+        return Err(make_segment_error("header checksum mismatch"));
+    }
+
+    let header = u64::from_le_bytes(header_bytes);
+    let payload_length = (header & MAX_PAYLOAD_LENGTH as u64) as usize;
+    let uncompressed_length = match compression {
+        Some(_) => ((header >> 17) & MAX_PAYLOAD_LENGTH as u64) as usize,
+        None => 0,
+    };
+
+    let payload_start = header_length + CRC24_LENGTH;
+    let segment_length = payload_start + payload_length + CRC32_LENGTH;
+    if src.len() < segment_length {
+        src.reserve(segment_length - src.len());
+        return Ok(None);
+    }
+
+    let mut segment = src.split_to(segment_length);
+    segment.advance(payload_start);
+    let payload = segment.split_to(payload_length).freeze();
+    if crc32(&payload) != segment.get_u32_le() {
+        return Err(make_segment_error("payload checksum mismatch"));
+    }
+
+    return match compression {
+        Some(compression) if uncompressed_length > 0 => Ok(Some(Bytes::from(
+            compression.decompress(&payload, uncompressed_length)?,
+        ))),
+        _ => Ok(Some(payload)),
+    };
+}
+
+fn make_segment_error(reason: &str) -> std::io::Error {
+    return std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Corrupted segment: {}", reason),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frame with the given stream id and a body of body_length bytes
+    fn frame(stream_id: u8, body_length: usize) -> Vec<u8> {
+        let mut frame = vec![0x05, 0, 0, stream_id, 0x07];
+        frame.extend_from_slice(&(body_length as u32).to_be_bytes());
+        frame.extend((0..body_length).map(|i| (i % 251) as u8));
+        return frame;
+    }
+
+    fn decode_all(mut buf: BytesMut, compression: Option<SegmentCompression>) -> (Vec<u8>, usize) {
+        let mut payloads = vec![];
+        let mut segments_count = 0;
+        while let Some(payload) = decode_segment(&mut buf, compression).unwrap() {
+            payloads.extend_from_slice(&payload);
+            segments_count += 1;
+        }
+        assert!(buf.is_empty());
+        return (payloads, segments_count);
+    }
+
+    #[test]
+    fn test_uncompressed_segment_layout() {
+        let frames = frame(1, 0);
+        let mut buf = BytesMut::new();
+        encode_segments(&frames, &mut buf, None);
+
+        // Payload length 9 with the self-contained bit set
+        assert_eq!(buf[..3], [9, 0, 0x02]);
+        let crc = crc24(&buf[..3]).to_le_bytes();
+        assert_eq!(buf[3..6], crc[..3]);
+        assert_eq!(buf[6..15], frames[..]);
+        assert_eq!(buf[15..], crc32(&frames).to_le_bytes());
+    }
+
+    #[test]
+    fn test_segments_roundtrip() {
+        let mut frames = frame(1, 100);
+        frames.extend(frame(2, 0));
+        frames.extend(frame(3, MAX_PAYLOAD_LENGTH));
+
+        let mut buf = BytesMut::new();
+        encode_segments(&frames, &mut buf, None);
+
+        // Small frames share a segment, the large one is split in two
+        let (payloads, segments_count) = decode_all(buf, None);
+        assert_eq!(payloads, frames);
+        assert_eq!(segments_count, 3);
+    }
+
+    #[test]
+    fn test_decode_waits_for_whole_segment() {
+        let mut encoded = BytesMut::new();
+        encode_segments(&frame(1, 10), &mut encoded, None);
+
+        let mut buf = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert!(decode_segment(&mut buf, None).unwrap().is_none());
+        buf.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert!(decode_segment(&mut buf, None).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_corrupted_segments_are_rejected() {
+        let mut encoded = BytesMut::new();
+        encode_segments(&frame(1, 10), &mut encoded, None);
+
+        let mut corrupted_header = encoded.clone();
+        corrupted_header[0] ^= 0x01;
+        assert!(decode_segment(&mut corrupted_header, None).is_err());
+
+        let mut corrupted_payload = encoded.clone();
+        corrupted_payload[10] ^= 0x01;
+        assert!(decode_segment(&mut corrupted_payload, None).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_compressed_segments_roundtrip() {
+        let compression = Some(SegmentCompression::Lz4);
+        let mut frames = frame(1, 0);
+        frames.extend(vec![0x05, 0, 0, 2, 0x07, 0, 0, 0x10, 0]);
+        frames.extend(vec![b'a'; 0x1000]);
+
+        let mut buf = BytesMut::new();
+        encode_segments(&frames, &mut buf, compression);
+        assert!(buf.len() < frames.len());
+
+        assert_eq!(decode_all(buf, compression).0, frames);
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_incompressible_segment_is_sent_uncompressed() {
+        let frames = frame(1, 0);
+        let mut buf = BytesMut::new();
+        encode_segments(&frames, &mut buf, Some(SegmentCompression::Lz4));
+
+        // Compressed length 9, uncompressed length 0 and the self-contained bit
+        assert_eq!(buf[..5], [9, 0, 0, 0, 0x04]);
+        assert_eq!(buf[8..17], frames[..]);
+        assert_eq!(decode_all(buf, Some(SegmentCompression::Lz4)).0, frames);
+    }
+}
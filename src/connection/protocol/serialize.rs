@@ -0,0 +1,573 @@
+use super::value::CqlValue;
+use bigdecimal::BigDecimal;
+use bytes::BufMut;
+use num_bigint::BigInt;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// Value that can be bound to a query, written as [value]
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 3)
+pub trait Value {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueTooBig;
+
+// Binding Unset leaves the column untouched, unlike null which deletes it
+// Only protocol v4 and newer have unset values, v3 servers reject them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unset;
+
+// Values bound to a single statement, already serialized
+// Values are either all positional or all named (bound to :name markers)
+// Clones share the buffer, so requests retried after preparing again don't copy it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SerializedValues {
+    serialized_values: Arc<Vec<u8>>,
+    values_num: u16,
+    contains_names: bool,
+    // Requests with unset values need protocol v4
+    contains_unset: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SerializeValuesError {
+    TooManyValues,
+    ValueTooBig,
+    MixingNamedAndNotNamedValues,
+    NamedValuesInBatch,
+    TooManyStatements,
+}
+
+impl std::fmt::Display for SerializeValuesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Self::TooManyValues => write!(f, "too many values, at most 65535 can be bound"),
+            Self::ValueTooBig => write!(f, "value is too big to be serialized"),
+            Self::MixingNamedAndNotNamedValues => {
+                write!(f, "named and positional values can't be bound together")
+            }
+            Self::NamedValuesInBatch => write!(f, "batches support only positional values"),
+            Self::TooManyStatements => {
+                write!(f, "too many statements, a batch can hold at most 65535")
+            }
+        };
+    }
+}
+
+impl std::error::Error for SerializeValuesError {}
+
+impl From<ValueTooBig> for SerializeValuesError {
+    fn from(_: ValueTooBig) -> SerializeValuesError {
+        return SerializeValuesError::ValueTooBig;
+    }
+}
+
+impl SerializedValues {
+    pub fn new() -> SerializedValues {
+        return Default::default();
+    }
+
+    pub fn add_value(&mut self, value: &impl Value) -> Result<(), SerializeValuesError> {
+        if self.contains_names {
+            return Err(SerializeValuesError::MixingNamedAndNotNamedValues);
+        }
+        return self.add(None, value);
+    }
+
+    // Binds value to the :name marker in the statement
+    pub fn add_named_value(
+        &mut self,
+        name: &str,
+        value: &impl Value,
+    ) -> Result<(), SerializeValuesError> {
+        if !self.contains_names && self.values_num > 0 {
+            return Err(SerializeValuesError::MixingNamedAndNotNamedValues);
+        }
+        self.contains_names = true;
+        return self.add(Some(name), value);
+    }
+
+    fn add(&mut self, name: Option<&str>, value: &impl Value) -> Result<(), SerializeValuesError> {
+        if self.values_num == u16::MAX {
+            return Err(SerializeValuesError::TooManyValues);
+        }
+
+        // Copied only if a clone shares the buffer
+        let serialized_values = Arc::make_mut(&mut self.serialized_values);
+        let len_before = serialized_values.len();
+        if let Some(name) = name {
+            // [string] name
+            if name.len() > u16::MAX as usize {
+                return Err(SerializeValuesError::ValueTooBig);
+            }
+            serialized_values.put_u16(name.len() as u16);
+            serialized_values.put_slice(name.as_bytes());
+        }
+
+        let value_start = serialized_values.len();
+        if let Err(error) = value.serialize(serialized_values) {
+            serialized_values.truncate(len_before);
+            return Err(error.into());
+        }
+        // Unset is [value] with length -2 and no bytes
+        if serialized_values[value_start..] == (-2i32).to_be_bytes() {
+            self.contains_unset = true;
+        }
+
+        self.values_num += 1;
+        return Ok(());
+    }
+
+    // Requests must set the "with names for values" flag for named values
+    pub fn has_names(&self) -> bool {
+        return self.contains_names;
+    }
+
+    pub fn has_unset(&self) -> bool {
+        return self.contains_unset;
+    }
+
+    pub fn len(&self) -> u16 {
+        return self.values_num;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.values_num == 0;
+    }
+
+    // [short] n followed by n [value], each preceded by [string] name if named
+    pub fn write_to_request(&self, buf: &mut impl BufMut) {
+        buf.put_u16(self.values_num);
+        buf.put_slice(&self.serialized_values);
+    }
+}
+
+// List of values bound to a statement, e.g. a tuple or a Vec
+pub trait ValueList {
+    fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError>;
+}
+
+impl ValueList for () {
+    fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError> {
+        return Ok(Cow::Owned(SerializedValues::new()));
+    }
+}
+
+impl ValueList for SerializedValues {
+    fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError> {
+        return Ok(Cow::Borrowed(self));
+    }
+}
+
+impl<T: Value> ValueList for [T] {
+    fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError> {
+        let mut result = SerializedValues::new();
+        for value in self {
+            result.add_value(value)?;
+        }
+        return Ok(Cow::Owned(result));
+    }
+}
+
+impl<T: Value> ValueList for Vec<T> {
+    fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError> {
+        return self.as_slice().serialized();
+    }
+}
+
+impl<L: ValueList + ?Sized> ValueList for &L {
+    fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError> {
+        return (*self).serialized();
+    }
+}
+
+// Named values
+impl<T: Value, S: BuildHasher> ValueList for HashMap<&str, T, S> {
+    fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError> {
+        let mut result = SerializedValues::new();
+        for (name, value) in self {
+            result.add_named_value(name, value)?;
+        }
+        return Ok(Cow::Owned(result));
+    }
+}
+
+macro_rules! impl_value_list_for_tuple {
+    ($($value_type:ident: $index:tt),+) => {
+        impl<$($value_type: Value),+> ValueList for ($($value_type,)+) {
+            fn serialized(&self) -> Result<Cow<'_, SerializedValues>, SerializeValuesError> {
+                let mut result = SerializedValues::new();
+                $(result.add_value(&self.$index)?;)+
+                return Ok(Cow::Owned(result));
+            }
+        }
+    };
+}
+
+impl_value_list_for_tuple!(T0: 0);
+impl_value_list_for_tuple!(T0: 0, T1: 1);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2, T3: 3);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2, T3: 3, T4: 4);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2, T3: 3, T4: 4, T5: 5);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2, T3: 3, T4: 4, T5: 5, T6: 6);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2, T3: 3, T4: 4, T5: 5, T6: 6, T7: 7);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2, T3: 3, T4: 4, T5: 5, T6: 6, T7: 7, T8: 8);
+impl_value_list_for_tuple!(T0: 0, T1: 1, T2: 2, T3: 3, T4: 4, T5: 5, T6: 6, T7: 7, T8: 8, T9: 9);
+impl_value_list_for_tuple!(
+    T0: 0, T1: 1, T2: 2, T3: 3, T4: 4, T5: 5, T6: 6, T7: 7, T8: 8, T9: 9, T10: 10
+);
+impl_value_list_for_tuple!(
+    T0: 0, T1: 1, T2: 2, T3: 3, T4: 4, T5: 5, T6: 6, T7: 7, T8: 8, T9: 9, T10: 10, T11: 11
+);
+
+// Writes [int] length followed by the bytes written by serialize_contents
+fn serialize_with_length(
+    buf: &mut Vec<u8>,
+    serialize_contents: impl FnOnce(&mut Vec<u8>) -> Result<(), ValueTooBig>,
+) -> Result<(), ValueTooBig> {
+    let length_pos = buf.len();
+    buf.put_i32(0);
+    serialize_contents(buf)?;
+
+    let length = i32::try_from(buf.len() - length_pos - 4).map_err(|_| ValueTooBig)?;
+    buf[length_pos..length_pos + 4].copy_from_slice(&length.to_be_bytes());
+    return Ok(());
+}
+
+fn serialize_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<(), ValueTooBig> {
+    return serialize_with_length(buf, |buf| {
+        buf.put_slice(bytes);
+        return Ok(());
+    });
+}
+
+// [int] count followed by elements, used by lists, sets and maps
+fn serialize_count(buf: &mut Vec<u8>, count: usize) -> Result<(), ValueTooBig> {
+    buf.put_i32(i32::try_from(count).map_err(|_| ValueTooBig)?);
+    return Ok(());
+}
+
+macro_rules! impl_value_for_number {
+    ($number_type:ty) => {
+        impl Value for $number_type {
+            fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+                return serialize_bytes(buf, &self.to_be_bytes());
+            }
+        }
+    };
+}
+
+impl_value_for_number!(i8);
+impl_value_for_number!(i16);
+impl_value_for_number!(i32);
+impl_value_for_number!(i64);
+impl_value_for_number!(f32);
+impl_value_for_number!(f64);
+
+impl Value for bool {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_bytes(buf, &[*self as u8]);
+    }
+}
+
+impl Value for str {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_bytes(buf, self.as_bytes());
+    }
+}
+
+impl Value for String {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_bytes(buf, self.as_bytes());
+    }
+}
+
+// Blob
+impl Value for Vec<u8> {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_bytes(buf, self);
+    }
+}
+
+impl Value for Uuid {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_bytes(buf, self.as_bytes());
+    }
+}
+
+impl Value for IpAddr {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return match self {
+            IpAddr::V4(address) => serialize_bytes(buf, &address.octets()),
+            IpAddr::V6(address) => serialize_bytes(buf, &address.octets()),
+        };
+    }
+}
+
+impl Value for BigInt {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_bytes(buf, &self.to_signed_bytes_be());
+    }
+}
+
+impl Value for BigDecimal {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        let (unscaled, scale) = self.as_bigint_and_exponent();
+        let scale = i32::try_from(scale).map_err(|_| ValueTooBig)?;
+        return serialize_with_length(buf, |buf| {
+            buf.put_i32(scale);
+            buf.put_slice(&unscaled.to_signed_bytes_be());
+            return Ok(());
+        });
+    }
+}
+
+impl Value for Unset {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        buf.put_i32(-2);
+        return Ok(());
+    }
+}
+
+// None is bound as null
+impl<T: Value> Value for Option<T> {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return match self {
+            Some(value) => value.serialize(buf),
+            None => {
+                buf.put_i32(-1);
+                return Ok(());
+            }
+        };
+    }
+}
+
+impl<T: Value + ?Sized> Value for &T {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return (*self).serialize(buf);
+    }
+}
+
+// List or set
+impl<T: Value> Value for [T] {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_with_length(buf, |buf| {
+            serialize_count(buf, self.len())?;
+            for element in self {
+                element.serialize(buf)?;
+            }
+            return Ok(());
+        });
+    }
+}
+
+impl<T: Value> Value for Vec<T> {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return self.as_slice().serialize(buf);
+    }
+}
+
+impl<K: Value, V: Value, S: BuildHasher> Value for HashMap<K, V, S> {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return serialize_with_length(buf, |buf| {
+            serialize_count(buf, self.len())?;
+            for (key, value) in self {
+                key.serialize(buf)?;
+                value.serialize(buf)?;
+            }
+            return Ok(());
+        });
+    }
+}
+
+impl Value for CqlValue {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        return match self {
+            CqlValue::Ascii(s) | CqlValue::Text(s) => s.serialize(buf),
+            CqlValue::BigInt(i) | CqlValue::Counter(i) | CqlValue::Timestamp(i) => i.serialize(buf),
+            CqlValue::Time(nanoseconds) => nanoseconds.serialize(buf),
+            CqlValue::Blob(b) | CqlValue::Custom(b) => b.serialize(buf),
+            CqlValue::Boolean(b) => b.serialize(buf),
+            CqlValue::Decimal(d) => d.serialize(buf),
+            CqlValue::Double(d) => d.serialize(buf),
+            CqlValue::Float(f) => f.serialize(buf),
+            CqlValue::Inet(address) => address.serialize(buf),
+            CqlValue::Int(i) => i.serialize(buf),
+            CqlValue::Uuid(uuid) | CqlValue::Timeuuid(uuid) => uuid.serialize(buf),
+            CqlValue::Varint(v) => v.serialize(buf),
+            CqlValue::Date(days) => serialize_bytes(buf, &days.to_be_bytes()),
+            CqlValue::SmallInt(i) => i.serialize(buf),
+            CqlValue::TinyInt(i) => i.serialize(buf),
+            CqlValue::Duration {
+                months,
+                days,
+                nanoseconds,
+            } => serialize_with_length(buf, |buf| {
+                write_vint(buf, *months as i64);
+                write_vint(buf, *days as i64);
+                write_vint(buf, *nanoseconds);
+                return Ok(());
+            }),
+            CqlValue::List(elements) | CqlValue::Set(elements) => elements.serialize(buf),
+            CqlValue::Map(entries) => serialize_with_length(buf, |buf| {
+                serialize_count(buf, entries.len())?;
+                for (key, value) in entries {
+                    key.serialize(buf)?;
+                    value.serialize(buf)?;
+                }
+                return Ok(());
+            }),
+            CqlValue::UserDefinedType { fields, .. } => serialize_with_length(buf, |buf| {
+                for (_, field) in fields {
+                    field.serialize(buf)?;
+                }
+                return Ok(());
+            }),
+            CqlValue::Tuple(elements) => serialize_with_length(buf, |buf| {
+                for element in elements {
+                    element.serialize(buf)?;
+                }
+                return Ok(());
+            }),
+        };
+    }
+}
+
+// Inverse of read_vint in value.rs
+fn write_vint(buf: &mut Vec<u8>, value: i64) {
+    let zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    let significant_bits = 64 - zigzag.leading_zeros() as usize;
+
+    // Each extra byte frees one bit in the first byte for the value
+    let mut extra_bytes = 0;
+    while extra_bytes < 8 && significant_bits > 7 * (extra_bytes + 1) {
+        extra_bytes += 1;
+    }
+
+    let first_byte_prefix = !(0xffu16 >> extra_bytes) as u8;
+    if extra_bytes == 8 {
+        buf.put_u8(first_byte_prefix);
+        buf.put_u64(zigzag);
+        return;
+    }
+
+    let mut encoded = zigzag.to_be_bytes()[7 - extra_bytes..].to_vec();
+    encoded[0] |= first_byte_prefix;
+    buf.put_slice(&encoded);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::protocol::types::ColumnType;
+
+    fn serialized(value: impl Value) -> Vec<u8> {
+        let mut buf = vec![];
+        value.serialize(&mut buf).unwrap();
+        return buf;
+    }
+
+    #[test]
+    fn test_basic_values() {
+        assert_eq!(serialized(7i32), vec![0, 0, 0, 4, 0, 0, 0, 7]);
+        assert_eq!(serialized("ab"), vec![0, 0, 0, 2, b'a', b'b']);
+        assert_eq!(serialized(None::<i32>), vec![0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(serialized(Unset), vec![0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(
+            serialized(vec![1i16, 2]),
+            vec![0, 0, 0, 16, 0, 0, 0, 2, 0, 0, 0, 2, 0, 1, 0, 0, 0, 2, 0, 2]
+        );
+    }
+
+    #[test]
+    fn test_cql_value_round_trip() {
+        let values = vec![
+            (
+                ColumnType::Duration,
+                CqlValue::Duration {
+                    months: 1,
+                    days: -1,
+                    nanoseconds: i64::MIN,
+                },
+            ),
+            (
+                ColumnType::Decimal,
+                CqlValue::Decimal("-12.345".parse().unwrap()),
+            ),
+            (
+                ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::Int)),
+                CqlValue::Map(vec![(CqlValue::Text("a".to_string()), CqlValue::Int(1))]),
+            ),
+            (
+                ColumnType::Tuple(vec![ColumnType::Int, ColumnType::Text]),
+                CqlValue::Tuple(vec![Some(CqlValue::Int(5)), None]),
+            ),
+        ];
+
+        for (typ, value) in values {
+            let buf = serialized(&value);
+            assert_eq!(CqlValue::deserialize(&typ, &buf[4..]), Ok(value));
+        }
+    }
+
+    #[test]
+    fn test_value_lists() {
+        let values = (1i32, "a", Unset).serialized().unwrap();
+        assert_eq!(values.len(), 3);
+
+        let mut buf = vec![];
+        values.write_to_request(&mut buf);
+        assert_eq!(
+            buf,
+            vec![0, 3, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 1, b'a', 0xff, 0xff, 0xff, 0xfe]
+        );
+
+        // Clones share the buffer until one of them adds a value
+        let mut extended = values.clone().into_owned();
+        extended.add_value(&2i32).unwrap();
+        assert_eq!(extended.len(), 4);
+        let mut unchanged = vec![];
+        values.write_to_request(&mut unchanged);
+        assert_eq!(unchanged, buf);
+
+        assert!(!values.has_names());
+        assert!(values.has_unset());
+        assert!(!(1i32, None::<i32>).serialized().unwrap().has_unset());
+        assert!(().serialized().unwrap().is_empty());
+        assert_eq!(vec![1i8, 2, 3].serialized().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_named_values() {
+        let mut values = SerializedValues::new();
+        values.add_named_value("a", &1i8).unwrap();
+        assert!(values.has_names());
+        assert_eq!(
+            values.add_value(&2i8),
+            Err(SerializeValuesError::MixingNamedAndNotNamedValues)
+        );
+
+        let mut buf = vec![];
+        values.write_to_request(&mut buf);
+        assert_eq!(buf, vec![0, 1, 0, 1, b'a', 0, 0, 0, 1, 1]);
+
+        let mut positional = SerializedValues::new();
+        positional.add_value(&1i8).unwrap();
+        assert_eq!(
+            positional.add_named_value("a", &2i8),
+            Err(SerializeValuesError::MixingNamedAndNotNamedValues)
+        );
+
+        let mut map = HashMap::new();
+        map.insert("b", 3i32);
+        assert!(map.serialized().unwrap().has_names());
+    }
+}
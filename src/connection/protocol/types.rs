@@ -1,5 +1,5 @@
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use bytes::{Buf, BufMut};
+use std::collections::HashMap;
 
 pub type StreamId = i16;
 
@@ -13,26 +13,311 @@ pub struct Header {
 }
 
 impl Header {
-    pub async fn serialize<T: AsyncWriteExt + Unpin>(
-        &self,
-        writer: &mut T,
-    ) -> Result<(), std::io::Error> {
-        writer.write_u8(self.protocol_version).await?;
-        writer.write_u8(self.flags).await?;
-        writer.write_i16(self.stream_id).await?;
-        writer.write_u8(self.opcode).await?;
-        return writer.write_u32(self.body_length).await;
-    }
-
-    pub async fn deserialize<T: AsyncReadExt + Unpin>(
-        reader: &mut T,
-    ) -> Result<Self, std::io::Error> {
-        let mut h: Header = Default::default();
-        h.protocol_version = reader.read_u8().await?;
-        h.flags = reader.read_u8().await?;
-        h.stream_id = reader.read_i16().await?;
-        h.opcode = reader.read_u8().await?;
-        h.body_length = reader.read_u32().await?;
-        return Ok(h);
+    // Serialized size, body length is in its last 4 bytes
+    pub const LENGTH: usize = 9;
+
+    // https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec#L126
+    pub const FLAG_COMPRESSION: u8 = 0x01;
+
+    // Set in the version byte of every response
+    pub const DIRECTION_RESPONSE: u8 = 0x80;
+
+    pub fn write_to(&self, buf: &mut impl BufMut) {
+        buf.put_u8(self.protocol_version);
+        buf.put_u8(self.flags);
+        buf.put_i16(self.stream_id);
+        buf.put_u8(self.opcode);
+        buf.put_u32(self.body_length);
+    }
+
+    pub fn deserialize(buf: &mut &[u8]) -> Result<Self, std::io::Error> {
+        let mut header = read_raw_bytes(buf, Header::LENGTH)?;
+        return Ok(Header {
+            protocol_version: header.get_u8(),
+            flags: header.get_u8(),
+            stream_id: header.get_i16(),
+            opcode: header.get_u8(),
+            body_length: header.get_u32(),
+        });
     }
 }
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 2.1)
+// Stream ids are [short] since v3, so all of them can be used with every supported version
+// v5 wraps frames sent after the handshake in checksummed segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    V3,
+    V4,
+    V5,
+}
+
+impl ProtocolVersion {
+    // Version byte of requests, responses have the direction bit 0x80 set on top of it
+    pub fn as_byte(self) -> u8 {
+        return match self {
+            Self::V3 => 0x03,
+            Self::V4 => 0x04,
+            Self::V5 => 0x05,
+        };
+    }
+
+    // Version with the given number, None for ones the driver doesn't speak
+    pub fn from_number(number: u8) -> Option<ProtocolVersion> {
+        return match number {
+            3 => Some(Self::V3),
+            4 => Some(Self::V4),
+            5 => Some(Self::V5),
+            _ => None,
+        };
+    }
+
+    // Next version to try when the server rejects this one
+    pub fn lower(self) -> Option<ProtocolVersion> {
+        return match self {
+            Self::V3 => None,
+            Self::V4 => Some(Self::V3),
+            Self::V5 => Some(Self::V4),
+        };
+    }
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 3)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Consistency {
+    Any,
+    #[default]
+    One,
+    Two,
+    Three,
+    Quorum,
+    All,
+    LocalQuorum,
+    EachQuorum,
+    Serial,
+    LocalSerial,
+    LocalOne,
+}
+
+// Consistency of the Paxos phase of lightweight transactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialConsistency {
+    Serial,
+    LocalSerial,
+}
+
+impl Consistency {
+    // [consistency] is written as [short]
+    pub fn code(self) -> u16 {
+        return match self {
+            Self::Any => 0x0000,
+            Self::One => 0x0001,
+            Self::Two => 0x0002,
+            Self::Three => 0x0003,
+            Self::Quorum => 0x0004,
+            Self::All => 0x0005,
+            Self::LocalQuorum => 0x0006,
+            Self::EachQuorum => 0x0007,
+            Self::Serial => 0x0008,
+            Self::LocalSerial => 0x0009,
+            Self::LocalOne => 0x000A,
+        };
+    }
+}
+
+impl From<SerialConsistency> for Consistency {
+    fn from(serial_consistency: SerialConsistency) -> Consistency {
+        return match serial_consistency {
+            SerialConsistency::Serial => Consistency::Serial,
+            SerialConsistency::LocalSerial => Consistency::LocalSerial,
+        };
+    }
+}
+
+// Helpers for reading notations defined in
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 3)
+// Unlike the bytes::Buf getters they return an error instead of panicking
+// when the body is too short.
+
+pub fn read_int(buf: &mut &[u8]) -> Result<i32, std::io::Error> {
+    ensure_remaining(buf, 4)?;
+    return Ok(buf.get_i32());
+}
+
+pub fn read_short(buf: &mut &[u8]) -> Result<u16, std::io::Error> {
+    ensure_remaining(buf, 2)?;
+    return Ok(buf.get_u16());
+}
+
+pub fn read_raw_bytes<'a>(buf: &mut &'a [u8], count: usize) -> Result<&'a [u8], std::io::Error> {
+    ensure_remaining(buf, count)?;
+    let (raw, rest) = buf.split_at(count);
+    *buf = rest;
+    return Ok(raw);
+}
+
+// [string]
+pub fn read_string(buf: &mut &[u8]) -> Result<String, std::io::Error> {
+    let len = read_short(buf)? as usize;
+    let raw = read_raw_bytes(buf, len)?;
+    return String::from_utf8(raw.to_vec())
+        .map_err(|_| make_parse_error("string is not valid UTF-8"));
+}
+
+// [string list]
+pub fn read_string_list(buf: &mut &[u8]) -> Result<Vec<String>, std::io::Error> {
+    let len = read_short(buf)? as usize;
+    let mut list = Vec::with_capacity(len);
+    for _ in 0..len {
+        list.push(read_string(buf)?);
+    }
+    return Ok(list);
+}
+
+// [string multimap]
+pub fn read_string_multimap(
+    buf: &mut &[u8],
+) -> Result<HashMap<String, Vec<String>>, std::io::Error> {
+    let len = read_short(buf)? as usize;
+    let mut multimap = HashMap::with_capacity(len);
+    for _ in 0..len {
+        let key = read_string(buf)?;
+        multimap.insert(key, read_string_list(buf)?);
+    }
+    return Ok(multimap);
+}
+
+// [bytes] - null (-1) and unset (-2) are both returned as None
+pub fn read_bytes_opt<'a>(buf: &mut &'a [u8]) -> Result<Option<&'a [u8]>, std::io::Error> {
+    let len = read_int(buf)?;
+    return match len {
+        -2 | -1 => Ok(None),
+        len if len < 0 => Err(make_parse_error("bad length of [bytes]")),
+        len => Ok(Some(read_raw_bytes(buf, len as usize)?)),
+    };
+}
+
+// [short bytes]
+pub fn read_short_bytes(buf: &mut &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let len = read_short(buf)? as usize;
+    return Ok(read_raw_bytes(buf, len)?.to_vec());
+}
+
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 4.2.5.2)
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnType {
+    Custom(String),
+    Ascii,
+    BigInt,
+    Blob,
+    Boolean,
+    Counter,
+    Decimal,
+    Double,
+    Float,
+    Int,
+    Timestamp,
+    Uuid,
+    Text,
+    Varint,
+    Timeuuid,
+    Inet,
+    Date,
+    Time,
+    SmallInt,
+    TinyInt,
+    Duration,
+    List(Box<ColumnType>),
+    Map(Box<ColumnType>, Box<ColumnType>),
+    Set(Box<ColumnType>),
+    UserDefinedType {
+        keyspace: String,
+        type_name: String,
+        field_types: Vec<(String, ColumnType)>,
+    },
+    Tuple(Vec<ColumnType>),
+}
+
+// Real schemas nest collections a few levels deep, more means the frame is corrupted
+// and following it could overflow the stack
+const MAX_TYPE_DEPTH: usize = 64;
+
+impl ColumnType {
+    // [option] with type id and, for custom and complex types, its value
+    pub fn deserialize(buf: &mut &[u8]) -> Result<ColumnType, std::io::Error> {
+        return Self::deserialize_nested(buf, 0);
+    }
+
+    // Depth is the number of types this one is nested in
+    fn deserialize_nested(buf: &mut &[u8], depth: usize) -> Result<ColumnType, std::io::Error> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(make_parse_error("column type nested too deeply"));
+        }
+        let inner = |buf: &mut &[u8]| Self::deserialize_nested(buf, depth + 1);
+
+        return match read_short(buf)? {
+            0x0000 => Ok(Self::Custom(read_string(buf)?)),
+            0x0001 => Ok(Self::Ascii),
+            0x0002 => Ok(Self::BigInt),
+            0x0003 => Ok(Self::Blob),
+            0x0004 => Ok(Self::Boolean),
+            0x0005 => Ok(Self::Counter),
+            0x0006 => Ok(Self::Decimal),
+            0x0007 => Ok(Self::Double),
+            0x0008 => Ok(Self::Float),
+            0x0009 => Ok(Self::Int),
+            0x000B => Ok(Self::Timestamp),
+            0x000C => Ok(Self::Uuid),
+            0x000D => Ok(Self::Text),
+            0x000E => Ok(Self::Varint),
+            0x000F => Ok(Self::Timeuuid),
+            0x0010 => Ok(Self::Inet),
+            0x0011 => Ok(Self::Date),
+            0x0012 => Ok(Self::Time),
+            0x0013 => Ok(Self::SmallInt),
+            0x0014 => Ok(Self::TinyInt),
+            0x0015 => Ok(Self::Duration),
+            0x0020 => Ok(Self::List(Box::new(inner(buf)?))),
+            0x0021 => Ok(Self::Map(Box::new(inner(buf)?), Box::new(inner(buf)?))),
+            0x0022 => Ok(Self::Set(Box::new(inner(buf)?))),
+            0x0030 => {
+                let keyspace = read_string(buf)?;
+                let type_name = read_string(buf)?;
+                let fields_count = read_short(buf)? as usize;
+                let mut field_types = Vec::with_capacity(fields_count);
+                for _ in 0..fields_count {
+                    let field_name = read_string(buf)?;
+                    field_types.push((field_name, inner(buf)?));
+                }
+                Ok(Self::UserDefinedType {
+                    keyspace,
+                    type_name,
+                    field_types,
+                })
+            }
+            0x0031 => {
+                let elements_count = read_short(buf)? as usize;
+                let mut element_types = Vec::with_capacity(elements_count);
+                for _ in 0..elements_count {
+                    element_types.push(inner(buf)?);
+                }
+                Ok(Self::Tuple(element_types))
+            }
+            _ => Err(make_parse_error("unknown column type")),
+        };
+    }
+}
+
+fn ensure_remaining(buf: &[u8], count: usize) -> Result<(), std::io::Error> {
+    if buf.len() < count {
+        return Err(make_parse_error("unexpected end of frame body"));
+    }
+    return Ok(());
+}
+
+pub fn make_parse_error(reason: &str) -> std::io::Error {
+    return std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid response: {}", reason),
+    );
+}
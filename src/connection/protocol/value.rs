@@ -0,0 +1,420 @@
+use super::types::ColumnType;
+use bigdecimal::BigDecimal;
+use num_bigint::BigInt;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+// Value of a cell, decoded according to its ColumnType
+// https://github.com/apache/cassandra/blob/trunk/doc/native_protocol_v4.spec (section 6)
+#[derive(Debug, Clone, PartialEq)]
+pub enum CqlValue {
+    Ascii(String),
+    BigInt(i64),
+    Blob(Vec<u8>),
+    Boolean(bool),
+    Counter(i64),
+    Decimal(BigDecimal),
+    Double(f64),
+    Float(f32),
+    Inet(IpAddr),
+    Int(i32),
+    // Milliseconds since unix epoch
+    Timestamp(i64),
+    Uuid(Uuid),
+    Text(String),
+    Varint(BigInt),
+    Timeuuid(Uuid),
+    // Days since unix epoch, with epoch at 2^31
+    Date(u32),
+    // Nanoseconds since midnight
+    Time(i64),
+    SmallInt(i16),
+    TinyInt(i8),
+    Duration {
+        months: i32,
+        days: i32,
+        nanoseconds: i64,
+    },
+    List(Vec<CqlValue>),
+    Map(Vec<(CqlValue, CqlValue)>),
+    Set(Vec<CqlValue>),
+    UserDefinedType {
+        keyspace: String,
+        type_name: String,
+        // Fields missing at the end of the serialized value are None
+        fields: Vec<(String, Option<CqlValue>)>,
+    },
+    Tuple(Vec<Option<CqlValue>>),
+    Custom(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    // Fixed-size value has a different size than its type requires
+    BadLength { expected: usize, actual: usize },
+    // Length prefix is negative but means neither null (-1) nor unset (-2)
+    BadLengthPrefix(i32),
+    // Value ends before the announced length
+    UnexpectedEnd,
+    // Null or unset found where a value is required, e.g. a list element
+    UnexpectedNull,
+    BadData(String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Self::BadLength { expected, actual } => write!(
+                f,
+                "bad value length: expected {} bytes, got {}",
+                expected, actual
+            ),
+            Self::BadLengthPrefix(len) => write!(f, "bad length prefix: {}", len),
+            Self::UnexpectedEnd => write!(f, "value ended unexpectedly"),
+            Self::UnexpectedNull => write!(f, "unexpected null value"),
+            Self::BadData(reason) => write!(f, "bad value: {}", reason),
+        };
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl CqlValue {
+    // Decodes a non null cell
+    pub fn deserialize(typ: &ColumnType, mut raw: &[u8]) -> Result<CqlValue, ParseError> {
+        let buf = &mut raw;
+
+        return Ok(match typ {
+            ColumnType::Custom(_) => CqlValue::Custom(buf.to_vec()),
+            ColumnType::Ascii => {
+                if !buf.is_ascii() {
+                    return Err(ParseError::BadData(
+                        "ascii value has non ascii bytes".into(),
+                    ));
+                }
+                CqlValue::Ascii(parse_utf8(buf)?)
+            }
+            ColumnType::BigInt => CqlValue::BigInt(i64::from_be_bytes(fixed(buf)?)),
+            ColumnType::Blob => CqlValue::Blob(buf.to_vec()),
+            ColumnType::Boolean => CqlValue::Boolean(fixed::<1>(buf)?[0] != 0),
+            ColumnType::Counter => CqlValue::Counter(i64::from_be_bytes(fixed(buf)?)),
+            ColumnType::Decimal => {
+                let scale = i32::from_be_bytes(take_fixed(buf)?);
+                let unscaled = BigInt::from_signed_bytes_be(buf);
+                CqlValue::Decimal(BigDecimal::new(unscaled, scale as i64))
+            }
+            ColumnType::Double => CqlValue::Double(f64::from_be_bytes(fixed(buf)?)),
+            ColumnType::Float => CqlValue::Float(f32::from_be_bytes(fixed(buf)?)),
+            ColumnType::Inet => CqlValue::Inet(match buf.len() {
+                4 => IpAddr::from(fixed::<4>(buf)?),
+                16 => IpAddr::from(fixed::<16>(buf)?),
+                actual => {
+                    return Err(ParseError::BadLength {
+                        expected: 4,
+                        actual,
+                    })
+                }
+            }),
+            ColumnType::Int => CqlValue::Int(i32::from_be_bytes(fixed(buf)?)),
+            ColumnType::Timestamp => CqlValue::Timestamp(i64::from_be_bytes(fixed(buf)?)),
+            ColumnType::Uuid => CqlValue::Uuid(Uuid::from_bytes(fixed(buf)?)),
+            ColumnType::Text => CqlValue::Text(parse_utf8(buf)?),
+            ColumnType::Varint => CqlValue::Varint(BigInt::from_signed_bytes_be(buf)),
+            ColumnType::Timeuuid => CqlValue::Timeuuid(Uuid::from_bytes(fixed(buf)?)),
+            ColumnType::Date => CqlValue::Date(u32::from_be_bytes(fixed(buf)?)),
+            ColumnType::Time => CqlValue::Time(i64::from_be_bytes(fixed(buf)?)),
+            ColumnType::SmallInt => CqlValue::SmallInt(i16::from_be_bytes(fixed(buf)?)),
+            ColumnType::TinyInt => CqlValue::TinyInt(i8::from_be_bytes(fixed(buf)?)),
+            ColumnType::Duration => {
+                let months = read_vint(buf)?;
+                let days = read_vint(buf)?;
+                let nanoseconds = read_vint(buf)?;
+                ensure_consumed(buf)?;
+                CqlValue::Duration {
+                    months: vint_to_i32(months)?,
+                    days: vint_to_i32(days)?,
+                    nanoseconds,
+                }
+            }
+            ColumnType::List(element_type) => CqlValue::List(parse_elements(buf, element_type)?),
+            ColumnType::Set(element_type) => CqlValue::Set(parse_elements(buf, element_type)?),
+            ColumnType::Map(key_type, value_type) => {
+                let count = read_count(buf)?;
+                let mut entries = Vec::with_capacity(count.min(buf.len()));
+                for _ in 0..count {
+                    let key = read_value(buf)?.ok_or(ParseError::UnexpectedNull)?;
+                    let value = read_value(buf)?.ok_or(ParseError::UnexpectedNull)?;
+                    entries.push((
+                        CqlValue::deserialize(key_type, key)?,
+                        CqlValue::deserialize(value_type, value)?,
+                    ));
+                }
+                ensure_consumed(buf)?;
+                CqlValue::Map(entries)
+            }
+            ColumnType::UserDefinedType {
+                keyspace,
+                type_name,
+                field_types,
+            } => {
+                let mut fields = Vec::with_capacity(field_types.len());
+                for (field_name, field_type) in field_types {
+                    // Fields added to the type after the value was written are absent
+                    let field = match buf.is_empty() {
+                        true => None,
+                        false => read_optional_value(buf, field_type)?,
+                    };
+                    fields.push((field_name.clone(), field));
+                }
+                ensure_consumed(buf)?;
+                CqlValue::UserDefinedType {
+                    keyspace: keyspace.clone(),
+                    type_name: type_name.clone(),
+                    fields,
+                }
+            }
+            ColumnType::Tuple(element_types) => {
+                let mut elements = Vec::with_capacity(element_types.len());
+                for element_type in element_types {
+                    elements.push(read_optional_value(buf, element_type)?);
+                }
+                ensure_consumed(buf)?;
+                CqlValue::Tuple(elements)
+            }
+        });
+    }
+
+    // Decodes a cell that can be null, unset cells are treated as null
+    pub fn deserialize_cell(
+        typ: &ColumnType,
+        raw: Option<&[u8]>,
+    ) -> Result<Option<CqlValue>, ParseError> {
+        return match raw {
+            Some(raw) => Ok(Some(CqlValue::deserialize(typ, raw)?)),
+            None => Ok(None),
+        };
+    }
+}
+
+// Whole value must have exactly N bytes
+fn fixed<const N: usize>(buf: &mut &[u8]) -> Result<[u8; N], ParseError> {
+    if buf.len() != N {
+        return Err(ParseError::BadLength {
+            expected: N,
+            actual: buf.len(),
+        });
+    }
+    return take_fixed(buf);
+}
+
+// Takes N bytes from the front of the value
+fn take_fixed<const N: usize>(buf: &mut &[u8]) -> Result<[u8; N], ParseError> {
+    let raw = take(buf, N)?;
+    let mut result = [0u8; N];
+    result.copy_from_slice(raw);
+    return Ok(result);
+}
+
+fn take<'a>(buf: &mut &'a [u8], count: usize) -> Result<&'a [u8], ParseError> {
+    if buf.len() < count {
+        return Err(ParseError::UnexpectedEnd);
+    }
+    let (taken, rest) = buf.split_at(count);
+    *buf = rest;
+    return Ok(taken);
+}
+
+fn ensure_consumed(buf: &[u8]) -> Result<(), ParseError> {
+    if !buf.is_empty() {
+        return Err(ParseError::BadData(format!(
+            "{} trailing bytes after value",
+            buf.len()
+        )));
+    }
+    return Ok(());
+}
+
+fn parse_utf8(buf: &[u8]) -> Result<String, ParseError> {
+    return String::from_utf8(buf.to_vec())
+        .map_err(|_| ParseError::BadData("text value is not valid UTF-8".into()));
+}
+
+fn read_count(buf: &mut &[u8]) -> Result<usize, ParseError> {
+    let count = i32::from_be_bytes(take_fixed(buf)?);
+    if count < 0 {
+        return Err(ParseError::BadData(format!(
+            "negative element count: {}",
+            count
+        )));
+    }
+    return Ok(count as usize);
+}
+
+// [bytes] inside a collection, UDT or tuple; None for null and unset
+fn read_value<'a>(buf: &mut &'a [u8]) -> Result<Option<&'a [u8]>, ParseError> {
+    let len = i32::from_be_bytes(take_fixed(buf)?);
+    return match len {
+        -2 | -1 => Ok(None),
+        len if len < 0 => Err(ParseError::BadLengthPrefix(len)),
+        len => Ok(Some(take(buf, len as usize)?)),
+    };
+}
+
+fn read_optional_value(buf: &mut &[u8], typ: &ColumnType) -> Result<Option<CqlValue>, ParseError> {
+    return CqlValue::deserialize_cell(typ, read_value(buf)?);
+}
+
+fn parse_elements(buf: &mut &[u8], element_type: &ColumnType) -> Result<Vec<CqlValue>, ParseError> {
+    let count = read_count(buf)?;
+    let mut elements = Vec::with_capacity(count.min(buf.len()));
+    for _ in 0..count {
+        let element = read_value(buf)?.ok_or(ParseError::UnexpectedNull)?;
+        elements.push(CqlValue::deserialize(element_type, element)?);
+    }
+    ensure_consumed(buf)?;
+    return Ok(elements);
+}
+
+// Variable length zigzag encoded integer used by duration
+// https://github.com/apache/cassandra/blob/trunk/src/java/org/apache/cassandra/utils/vint/VIntCoding.java
+fn read_vint(buf: &mut &[u8]) -> Result<i64, ParseError> {
+    let first_byte = take_fixed::<1>(buf)?[0];
+    let extra_bytes = first_byte.leading_ones() as usize;
+
+    let mut value = (first_byte as u64) & (0xffu64 >> extra_bytes);
+    for byte in take(buf, extra_bytes)? {
+        value = (value << 8) | (*byte as u64);
+    }
+
+    return Ok(((value >> 1) as i64) ^ -((value & 1) as i64));
+}
+
+fn vint_to_i32(value: i64) -> Result<i32, ParseError> {
+    return i32::try_from(value)
+        .map_err(|_| ParseError::BadData(format!("duration field out of range: {}", value)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_size_values() {
+        assert_eq!(
+            CqlValue::deserialize(&ColumnType::Int, &[0, 0, 1, 0]),
+            Ok(CqlValue::Int(256))
+        );
+        assert_eq!(
+            CqlValue::deserialize(&ColumnType::Boolean, &[1]),
+            Ok(CqlValue::Boolean(true))
+        );
+        assert_eq!(
+            CqlValue::deserialize(&ColumnType::Inet, &[127, 0, 0, 1]),
+            Ok(CqlValue::Inet("127.0.0.1".parse().unwrap()))
+        );
+        assert_eq!(
+            CqlValue::deserialize(&ColumnType::BigInt, &[0, 0, 1]),
+            Err(ParseError::BadLength {
+                expected: 8,
+                actual: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_variable_size_values() {
+        assert_eq!(
+            CqlValue::deserialize(&ColumnType::Varint, &[0xff, 0x00]),
+            Ok(CqlValue::Varint(BigInt::from(-256)))
+        );
+        assert_eq!(
+            CqlValue::deserialize(&ColumnType::Decimal, &[0, 0, 0, 2, 0x04, 0xd2]),
+            Ok(CqlValue::Decimal("12.34".parse().unwrap()))
+        );
+        assert!(CqlValue::deserialize(&ColumnType::Ascii, "zażółć".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_duration() {
+        // months: 1, days: -1, nanoseconds: 200
+        let raw = [0x02, 0x01, 0x81, 0x90];
+        assert_eq!(
+            CqlValue::deserialize(&ColumnType::Duration, &raw),
+            Ok(CqlValue::Duration {
+                months: 1,
+                days: -1,
+                nanoseconds: 200,
+            })
+        );
+    }
+
+    #[test]
+    fn test_collections() {
+        let list_type = ColumnType::List(Box::new(ColumnType::Int));
+        let raw = [0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 2];
+        assert_eq!(
+            CqlValue::deserialize(&list_type, &raw),
+            Ok(CqlValue::List(vec![CqlValue::Int(1), CqlValue::Int(2)]))
+        );
+
+        let map_type = ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::TinyInt));
+        let raw = [0, 0, 0, 1, 0, 0, 0, 1, b'a', 0, 0, 0, 1, 7];
+        assert_eq!(
+            CqlValue::deserialize(&map_type, &raw),
+            Ok(CqlValue::Map(vec![(
+                CqlValue::Text("a".to_string()),
+                CqlValue::TinyInt(7)
+            )]))
+        );
+
+        let null_element = [0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(
+            CqlValue::deserialize(&list_type, &null_element),
+            Err(ParseError::UnexpectedNull)
+        );
+
+        let bad_prefix = [0, 0, 0, 1, 0xff, 0xff, 0xff, 0xf0];
+        assert_eq!(
+            CqlValue::deserialize(&list_type, &bad_prefix),
+            Err(ParseError::BadLengthPrefix(-16))
+        );
+
+        let too_long = [0, 0, 0, 1, 0, 0, 0, 8, 0, 0];
+        assert_eq!(
+            CqlValue::deserialize(&list_type, &too_long),
+            Err(ParseError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn test_tuple_and_udt() {
+        let tuple_type = ColumnType::Tuple(vec![ColumnType::Int, ColumnType::Text]);
+        let raw = [0, 0, 0, 4, 0, 0, 0, 5, 0xff, 0xff, 0xff, 0xfe];
+        assert_eq!(
+            CqlValue::deserialize(&tuple_type, &raw),
+            Ok(CqlValue::Tuple(vec![Some(CqlValue::Int(5)), None]))
+        );
+
+        let udt_type = ColumnType::UserDefinedType {
+            keyspace: "ks".to_string(),
+            type_name: "address".to_string(),
+            field_types: vec![
+                ("street".to_string(), ColumnType::Text),
+                ("number".to_string(), ColumnType::Int),
+            ],
+        };
+        let raw = [0, 0, 0, 1, b'x'];
+        assert_eq!(
+            CqlValue::deserialize(&udt_type, &raw),
+            Ok(CqlValue::UserDefinedType {
+                keyspace: "ks".to_string(),
+                type_name: "address".to_string(),
+                fields: vec![
+                    ("street".to_string(), Some(CqlValue::Text("x".to_string()))),
+                    ("number".to_string(), None),
+                ],
+            })
+        );
+    }
+}
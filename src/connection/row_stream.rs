@@ -0,0 +1,112 @@
+use super::{ColumnSpec, QueryError, QueryResult, Row, SerializedValues};
+use crate::{PreparedStatement, Query};
+use bytes::Bytes;
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[derive(Clone)]
+pub enum PagedStatement {
+    Query(Query, SerializedValues),
+    Prepared(PreparedStatement, SerializedValues),
+}
+
+pub type PageFuture<'a, C> =
+    Pin<Box<dyn Future<Output = (C, Result<QueryResult, QueryError>)> + Send + 'a>>;
+
+// Connection reference able to fetch a single page of a statement
+// The future gives the reference back, so it can also be an exclusive one
+pub trait PageFetcher<'a>: Sized + Send + 'a {
+    fn fetch_page(
+        self,
+        statement: PagedStatement,
+        paging_state: Option<Bytes>,
+    ) -> PageFuture<'a, Self>;
+}
+
+enum FetchState<'a, C> {
+    // Waiting until rows of the current page are consumed
+    Idle(C),
+    Fetching(PageFuture<'a, C>),
+    Finished,
+}
+
+// RowStream yields rows of a paged query, fetching next pages transparently
+pub struct RowStream<'a, C> {
+    statement: PagedStatement,
+    state: FetchState<'a, C>,
+    current_rows: std::vec::IntoIter<Row>,
+    paging_state: Option<Bytes>,
+    col_specs: Option<Vec<ColumnSpec>>,
+}
+
+impl<'a, C: PageFetcher<'a>> RowStream<'a, C> {
+    pub(crate) fn new(connection: C, statement: PagedStatement) -> RowStream<'a, C> {
+        let first_page = connection.fetch_page(statement.clone(), None);
+        return RowStream {
+            statement,
+            state: FetchState::Fetching(first_page),
+            current_rows: Vec::new().into_iter(),
+            paging_state: None,
+            col_specs: None,
+        };
+    }
+
+    // Columns of the returned rows, known once the first page arrives
+    pub fn get_col_specs(&self) -> Option<&[ColumnSpec]> {
+        return self.col_specs.as_deref();
+    }
+}
+
+// RowStream only moves the page future, which is already pinned in a Box
+impl<'a, C> Unpin for RowStream<'a, C> {}
+
+impl<'a, C: PageFetcher<'a>> Stream for RowStream<'a, C> {
+    type Item = Result<Row, QueryError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(row) = self.current_rows.next() {
+                return Poll::Ready(Some(Ok(row)));
+            }
+
+            match std::mem::replace(&mut self.state, FetchState::Finished) {
+                FetchState::Idle(connection) => {
+                    if self.paging_state.is_none() {
+                        // The last page has been consumed
+                        return Poll::Ready(None);
+                    }
+                    let paging_state = self.paging_state.clone();
+                    let page = connection.fetch_page(self.statement.clone(), paging_state);
+                    self.state = FetchState::Fetching(page);
+                }
+                FetchState::Fetching(mut page_future) => {
+                    let (connection, result) = match page_future.as_mut().poll(context) {
+                        Poll::Ready(page) => page,
+                        Poll::Pending => {
+                            self.state = FetchState::Fetching(page_future);
+                            return Poll::Pending;
+                        }
+                    };
+
+                    let rows = match result {
+                        Ok(QueryResult { rows: Some(rows) }) => rows,
+                        Ok(QueryResult { rows: None }) => {
+                            return Poll::Ready(Some(Err(QueryError::UnexpectedResponse)))
+                        }
+                        Err(error) => return Poll::Ready(Some(Err(error))),
+                    };
+
+                    self.paging_state = rows.metadata.paging_state;
+                    if self.col_specs.is_none() {
+                        self.col_specs = Some(rows.metadata.col_specs);
+                    }
+                    self.current_rows = rows.rows.into_iter();
+                    self.state = FetchState::Idle(connection);
+                }
+                FetchState::Finished => return Poll::Ready(None),
+            };
+        }
+    }
+}
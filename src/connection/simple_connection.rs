@@ -1,56 +1,427 @@
-use tokio::io::{BufReader, BufWriter};
-use tokio::net::{TcpStream, ToSocketAddrs, tcp::{OwnedReadHalf, OwnedWriteHalf}};
+use super::handshake::{self, ConnectedTransport, ConnectionFeatures};
+use super::protocol::codec::{read_response_frame, RequestEncoder, ResponseReader};
+use super::protocol::{Request, Response, ResultMessage};
+use super::row_stream::{PageFetcher, PageFuture, PagedStatement, RowStream};
+use super::statements::{self, StatementSender};
+use super::transport::{TransportReadHalf, TransportWriteHalf};
 use super::QueryError;
-use crate::Query;
-use super::protocol::{Request, Response};
-use tokio::io::AsyncWriteExt;
+use super::{ConnectionConfig, QueryResult, ValueList};
+use crate::{Batch, PreparedStatement, Query};
+use bytes::{Bytes, BytesMut};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::ToSocketAddrs;
 
+// Connection sending one request at a time, waiting for its response before the next one
+// Responses aren't matched to requests by stream id, so once a request times out or reading
+// fails midway the connection is unusable, its late response would answer the next request
 pub struct Connection {
-    tcp_reader: BufReader<OwnedReadHalf>,
-    tcp_writer: BufWriter<OwnedWriteHalf>,
+    tcp_reader: ResponseReader<TransportReadHalf>,
+    tcp_writer: TransportWriteHalf,
+    encoder: RequestEncoder,
+    // Reused for every request, so encoding doesn't allocate once it has grown enough
+    frames_buffer: BytesMut,
+    config: ConnectionConfig,
+    features: ConnectionFeatures,
+    // Set once the connection is unusable, every following request fails with it
+    connection_error: Option<Arc<std::io::Error>>,
 }
 
 impl Connection {
     pub async fn new<A: ToSocketAddrs>(address: A) -> Result<Self, std::io::Error> {
-        let tcp_stream: TcpStream = tokio::net::TcpStream::connect(address).await?;
-        let (tcp_read_half, tcp_write_half) = tcp_stream.into_split();
-        let (mut tcp_reader, mut tcp_writer) = (
-            BufReader::new(tcp_read_half),
-            BufWriter::new(tcp_write_half),
-        );
-
-        let startup_request = Request::Startup;
-        startup_request.write(1, &mut tcp_writer).await?;
-        tcp_writer.flush().await?;
-
-        let (response, _stream_id) = Response::read(&mut tcp_reader).await?;
-        match response {
-            Response::Ready => { /* Ok connection succesfull */ }
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to connect to server - response was not Ready",
-                ))
+        return Connection::new_with_config(address, Default::default()).await;
+    }
+
+    pub async fn new_with_config<A: ToSocketAddrs>(
+        address: A,
+        config: ConnectionConfig,
+    ) -> Result<Self, std::io::Error> {
+        let connected = handshake::connect(address, &config).await?;
+        return Ok(Connection::start(connected, config));
+    }
+
+    #[cfg(unix)]
+    pub async fn new_unix<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        return Connection::new_unix_with_config(path, Default::default()).await;
+    }
+
+    #[cfg(unix)]
+    pub async fn new_unix_with_config<P: AsRef<Path>>(
+        path: P,
+        config: ConnectionConfig,
+    ) -> Result<Self, std::io::Error> {
+        let connected = handshake::connect_unix(path.as_ref(), &config).await?;
+        return Ok(Connection::start(connected, config));
+    }
+
+    // Stream is used as it is, e.g. TLS from the config isn't applied to it
+    pub async fn from_stream<S>(stream: S) -> Result<Self, std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        return Connection::from_stream_with_config(stream, Default::default()).await;
+    }
+
+    // Only protocol_version is tried, the stream can't be reopened with a lower one
+    pub async fn from_stream_with_config<S>(
+        stream: S,
+        config: ConnectionConfig,
+    ) -> Result<Self, std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let connected = handshake::connect_stream(Box::new(stream), &config).await?;
+        return Ok(Connection::start(connected, config));
+    }
+
+    fn start(connected: ConnectedTransport, config: ConnectionConfig) -> Connection {
+        // Writes aren't buffered, each request is encoded whole and written at once
+        let (tcp_reader, tcp_writer, features) = connected;
+        let encoder =
+            RequestEncoder::new(features.get_protocol_version(), features.get_compression());
+
+        return Connection {
+            tcp_reader,
+            tcp_writer,
+            encoder,
+            frames_buffer: BytesMut::new(),
+            config,
+            features,
+            connection_error: None,
+        };
+    }
+
+    pub async fn query(
+        &mut self,
+        query_to_perform: Query,
+        values: impl ValueList,
+    ) -> Result<QueryResult, QueryError> {
+        return self.query_paged(query_to_perform, values, None).await;
+    }
+
+    // Fetches a single page, starting where the page with paging_state ended
+    pub async fn query_paged(
+        &mut self,
+        query_to_perform: Query,
+        values: impl ValueList,
+        paging_state: Option<Bytes>,
+    ) -> Result<QueryResult, QueryError> {
+        let values = values.serialized()?.into_owned();
+        let request =
+            statements::query_request(&query_to_perform, values, paging_state, &self.config);
+
+        return Ok(self
+            .send_request(&request, query_to_perform.get_timeout())
+            .await?
+            .into());
+    }
+
+    // Streams rows of all pages, fetching the next page when the current one is consumed
+    // Borrows the connection, so no other requests can be sent until the stream is dropped
+    pub fn query_iter(
+        &mut self,
+        query_to_perform: Query,
+        values: impl ValueList,
+    ) -> Result<RowStream<'_, &mut Connection>, QueryError> {
+        let values = values.serialized()?.into_owned();
+        return Ok(RowStream::new(
+            self,
+            PagedStatement::Query(query_to_perform, values),
+        ));
+    }
+
+    pub async fn prepare(&mut self, query_text: &str) -> Result<PreparedStatement, QueryError> {
+        let request: Request = Request::Prepare(query_text.to_string());
+
+        let result = self.send_request(&request, None).await?;
+        return statements::prepared_statement(result, query_text);
+    }
+
+    pub async fn execute(
+        &mut self,
+        prepared_statement: &PreparedStatement,
+        values: impl ValueList,
+    ) -> Result<QueryResult, QueryError> {
+        return self.execute_paged(prepared_statement, values, None).await;
+    }
+
+    // Fetches a single page, starting where the page with paging_state ended
+    pub async fn execute_paged(
+        &mut self,
+        prepared_statement: &PreparedStatement,
+        values: impl ValueList,
+        paging_state: Option<Bytes>,
+    ) -> Result<QueryResult, QueryError> {
+        let values = values.serialized()?.into_owned();
+        let result = statements::execute(self, prepared_statement, values, paging_state).await?;
+        return Ok(result.into());
+    }
+
+    // Streams rows of all pages, fetching the next page when the current one is consumed
+    // Borrows the connection, so no other requests can be sent until the stream is dropped
+    pub fn execute_iter(
+        &mut self,
+        prepared_statement: PreparedStatement,
+        values: impl ValueList,
+    ) -> Result<RowStream<'_, &mut Connection>, QueryError> {
+        let values = values.serialized()?.into_owned();
+        return Ok(RowStream::new(
+            self,
+            PagedStatement::Prepared(prepared_statement, values),
+        ));
+    }
+
+    pub async fn batch(&mut self, batch: &Batch) -> Result<QueryResult, QueryError> {
+        return Ok(statements::batch(self, batch).await?.into());
+    }
+
+    // Connection is defunct once a request timed out or reading or writing failed,
+    // all requests sent on it will fail, it should be dropped and replaced with a new one
+    pub fn is_defunct(&self) -> bool {
+        return self.connection_error.is_some();
+    }
+
+    // Options negotiated with the server when the connection was opened
+    pub fn get_features(&self) -> &ConnectionFeatures {
+        return &self.features;
+    }
+
+    // Statement timeout overrides the one from config
+    async fn send_request(
+        &mut self,
+        request: &Request,
+        timeout: Option<Duration>,
+    ) -> Result<ResultMessage, QueryError> {
+        statements::check_protocol_version(request, &self.features)?;
+        if let Some(error) = &self.connection_error {
+            return Err(QueryError::ConnectionBroken(error.clone()));
+        }
+
+        let result = match timeout.or(self.config.request_timeout) {
+            Some(timeout) => {
+                match tokio::time::timeout(timeout, self.write_and_read(request)).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        self.connection_error = Some(Arc::new(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "a request timed out, its response would answer the next one",
+                        )));
+                        return Err(QueryError::Timeout);
+                    }
+                }
             }
+            None => self.write_and_read(request).await,
         };
 
-        return Ok(Connection {
-            tcp_reader: tcp_reader,
-            tcp_writer: tcp_writer,
+        match result {
+            // Invalid response fails only the request it answers
+            Ok(response) => return statements::result_from_response(response?),
+            Err(io_error) => {
+                let error = Arc::new(io_error);
+                self.connection_error = Some(error.clone());
+                return Err(QueryError::ConnectionBroken(error));
+            }
+        };
+    }
+
+    // Outer error leaves the connection out of sync, inner one only means the body is invalid
+    async fn write_and_read(
+        &mut self,
+        request: &Request,
+    ) -> Result<Result<Response, std::io::Error>, std::io::Error> {
+        self.encoder.encode(request, 1, &mut self.frames_buffer);
+        self.encoder.finish(&mut self.frames_buffer);
+        // TLS buffers records, flushing sends them, plain TCP has nothing to flush
+        let mut write_result = self.tcp_writer.write_all(&self.frames_buffer).await;
+        if write_result.is_ok() {
+            write_result = self.tcp_writer.flush().await;
+        }
+        self.frames_buffer.clear();
+        write_result?;
+
+        let (response, _stream_id) = read_response_frame(&mut self.tcp_reader).await?;
+        return Ok(response);
+    }
+}
+
+impl StatementSender for Connection {
+    fn get_config(&self) -> &ConnectionConfig {
+        return &self.config;
+    }
+
+    async fn send(
+        &mut self,
+        request: Request,
+        timeout: Option<Duration>,
+    ) -> Result<ResultMessage, QueryError> {
+        return self.send_request(&request, timeout).await;
+    }
+
+    async fn prepare(&mut self, query_text: &str) -> Result<PreparedStatement, QueryError> {
+        return Connection::prepare(self, query_text).await;
+    }
+}
+
+impl<'a> PageFetcher<'a> for &'a mut Connection {
+    fn fetch_page(
+        self,
+        statement: PagedStatement,
+        paging_state: Option<Bytes>,
+    ) -> PageFuture<'a, Self> {
+        return Box::pin(async move {
+            let result = match statement {
+                PagedStatement::Query(query, values) => {
+                    self.query_paged(query, values, paging_state).await
+                }
+                PagedStatement::Prepared(prepared, values) => {
+                    self.execute_paged(&prepared, values, paging_state).await
+                }
+            };
+            return (self, result);
         });
     }
+}
 
-    pub async fn query(&mut self, query_to_perform: Query) -> Result<(), QueryError> {
-        let request: Request = Request::Query(query_to_perform.get_query_text());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::test_utils::*;
+    use futures::StreamExt;
+    use tokio::net::TcpListener;
 
-        request.write(1, &mut self.tcp_writer).await ?;
-        self.tcp_writer.flush().await ?;
+    #[test]
+    fn test_execute_reprepares_unprepared_statement() {
+        let prepared_result = [
+            0, 0, 0, 4, 0, 1, 0x2a, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0,
+        ];
+        let unprepared_error = [0, 0, 0x25, 0, 0, 1, b'?', 0, 1, 0x2a];
 
-        match Response::read(&mut self.tcp_reader).await {
-            Err(io_error) => return Err(QueryError::IOError(io_error)),
-            Ok((Response::Result, _)) => return Ok(()),
-            Ok((Response::Error(message), _)) => return Err(QueryError::Message(message)),
-            _ => panic!("Strange response!"),
-        };
+        tokio_test::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                assert_eq!(read_request(&mut stream).await.1, 0x09);
+                write_response(&mut stream, 1, RESULT, &prepared_result).await;
+
+                assert_eq!(read_request(&mut stream).await.1, 0x0A);
+                write_response(&mut stream, 1, ERROR, &unprepared_error).await;
+
+                let (_, opcode, body) = read_request(&mut stream).await;
+                assert_eq!((opcode, body), (0x09, b"\0\0\0\x01q".to_vec()));
+                write_response(&mut stream, 1, RESULT, &prepared_result).await;
+
+                let (_, opcode, body) = read_request(&mut stream).await;
+                assert_eq!((opcode, &body[..3]), (0x0A, &[0, 1, 0x2a][..]));
+                write_response(&mut stream, 1, RESULT, &VOID_RESULT).await;
+            });
+
+            let mut connection = Connection::new(address).await.unwrap();
+            let prepared = connection.prepare("q").await.unwrap();
+            let result = connection.execute(&prepared, ()).await.unwrap();
+            assert_eq!(result, QueryResult { rows: None });
+
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_timed_out_request_makes_connection_defunct() {
+        tokio_test::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+                // Never answered, later requests must not be sent
+                read_request(&mut stream).await;
+                return stream;
+            });
+
+            let mut connection = Connection::new(address).await.unwrap();
+            let mut query = Query::new("a");
+            query.set_timeout(Some(Duration::from_millis(50)));
+            let result = connection.query(query, ()).await;
+            assert!(matches!(result, Err(QueryError::Timeout)));
+            assert!(connection.is_defunct());
+
+            let result = connection.query(Query::new("b"), ()).await;
+            assert!(matches!(result, Err(QueryError::ConnectionBroken(_))));
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_request_timeout_from_config() {
+        tokio_test::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+                read_request(&mut stream).await;
+                return stream;
+            });
+
+            let config = ConnectionConfig {
+                request_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            };
+            let mut connection = Connection::new_with_config(address, config).await.unwrap();
+            let result = connection.query(Query::new("a"), ()).await;
+            assert!(matches!(result, Err(QueryError::Timeout)));
+
+            let _server_stream = server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_query_iter_fetches_all_pages() {
+        // no_metadata | has_more_pages, one column, paging state 0xab, one row with 0x01
+        let first_page = [
+            0, 0, 0, 2, 0, 0, 0, 6, 0, 0, 0, 1, 0, 0, 0, 1, 0xab, 0, 0, 0, 1, 0, 0, 0, 1, 0x01,
+        ];
+        // no_metadata, one column, one row with 0x02
+        let last_page = [
+            0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0x02,
+        ];
+
+        tokio_test::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut stream = accept_connection(&listener).await;
+
+                let (_, _, body) = read_request(&mut stream).await;
+                // consistency, flags with page size, page size
+                assert_eq!(body[5..], [0, 1, 0x04, 0, 0, 0, 1]);
+                write_response(&mut stream, 1, RESULT, &first_page).await;
+
+                let (_, _, body) = read_request(&mut stream).await;
+                // paging state of the first page is sent back
+                assert_eq!(body[5..], [0, 1, 0x0C, 0, 0, 0, 1, 0, 0, 0, 1, 0xab]);
+                write_response(&mut stream, 1, RESULT, &last_page).await;
+            });
+
+            let mut connection = Connection::new(address).await.unwrap();
+            let mut query = Query::new("q");
+            query.set_page_size(Some(1));
+
+            let rows: Vec<_> = connection.query_iter(query, ()).unwrap().collect().await;
+            let cells: Vec<_> = rows
+                .into_iter()
+                .map(|row| row.unwrap().get_raw(0).map(<[u8]>::to_vec))
+                .collect();
+            assert_eq!(cells, vec![Some(vec![0x01]), Some(vec![0x02])]);
+
+            server.await.unwrap();
+        });
     }
 }
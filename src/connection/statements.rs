@@ -0,0 +1,282 @@
+// Turns statements into protocol requests and responses back into results,
+// shared by both connection types
+
+use super::protocol::request::{BatchParameters, BatchQuery, QueryParameters};
+use super::protocol::{Request, Response, ResultMessage};
+use super::{ConnectionConfig, ConnectionFeatures, QueryError, SerializedValues};
+use crate::query::BatchStatement;
+use crate::{Batch, PreparedStatement, Query};
+use bytes::Bytes;
+use std::time::Duration;
+
+// Requests needed to retry statements the server no longer has prepared,
+// implemented by both connection types
+pub trait StatementSender {
+    fn get_config(&self) -> &ConnectionConfig;
+
+    async fn send(
+        &mut self,
+        request: Request,
+        timeout: Option<Duration>,
+    ) -> Result<ResultMessage, QueryError>;
+
+    async fn prepare(&mut self, query_text: &str) -> Result<PreparedStatement, QueryError>;
+}
+
+pub fn query_request(
+    query: &Query,
+    values: SerializedValues,
+    paging_state: Option<Bytes>,
+    config: &ConnectionConfig,
+) -> Request {
+    let parameters = QueryParameters {
+        consistency: query
+            .get_consistency()
+            .unwrap_or(config.default_consistency),
+        serial_consistency: query
+            .get_serial_consistency()
+            .or(config.default_serial_consistency),
+        values,
+        page_size: query.get_page_size(),
+        paging_state,
+        keyspace: query.get_keyspace().map(str::to_string),
+        now_in_seconds: query.get_now_in_seconds(),
+    };
+    return Request::Query(query.get_query_text(), parameters);
+}
+
+// Ids are taken from current, they change when the statement gets prepared again
+pub fn execute_request(
+    prepared_statement: &PreparedStatement,
+    current: &PreparedStatement,
+    values: SerializedValues,
+    paging_state: Option<Bytes>,
+    config: &ConnectionConfig,
+) -> Request {
+    let parameters = QueryParameters {
+        consistency: prepared_statement
+            .get_consistency()
+            .unwrap_or(config.default_consistency),
+        serial_consistency: prepared_statement
+            .get_serial_consistency()
+            .or(config.default_serial_consistency),
+        values,
+        page_size: prepared_statement.get_page_size(),
+        paging_state,
+        keyspace: None,
+        now_in_seconds: prepared_statement.get_now_in_seconds(),
+    };
+    return Request::Execute(
+        current.get_id().to_vec(),
+        current.get_result_metadata_id().to_vec(),
+        parameters,
+    );
+}
+
+// Ids of prepared statements are passed separately, in the order of prepared statements
+// in the batch, they change when the statements get prepared again
+pub fn batch_request(
+    batch: &Batch,
+    prepared_ids: Option<&[Vec<u8>]>,
+    config: &ConnectionConfig,
+) -> Request {
+    let mut prepared_ids = prepared_ids.map(|ids| ids.iter());
+
+    let mut parameters = BatchParameters {
+        batch_type: batch.get_type(),
+        queries: Vec::with_capacity(batch.get_statements().len()),
+        consistency: batch
+            .get_consistency()
+            .unwrap_or(config.default_consistency),
+        serial_consistency: batch
+            .get_serial_consistency()
+            .or(config.default_serial_consistency),
+        timestamp: batch.get_timestamp(),
+        keyspace: batch.get_keyspace().map(str::to_string),
+        now_in_seconds: batch.get_now_in_seconds(),
+    };
+    for (statement, values) in batch.get_statements() {
+        let query = match statement {
+            BatchStatement::Query(query) => BatchQuery::Query(query.get_query_text()),
+            BatchStatement::PreparedStatement(prepared) => {
+                let id = match prepared_ids.as_mut().and_then(|ids| ids.next()) {
+                    Some(id) => id.clone(),
+                    None => prepared.get_id().to_vec(),
+                };
+                BatchQuery::Prepared(id)
+            }
+        };
+        parameters.queries.push((query, values.clone()));
+    }
+    return Request::Batch(parameters);
+}
+
+// Prepared statements of the batch, in order
+pub fn batch_prepared_statements(batch: &Batch) -> impl Iterator<Item = &PreparedStatement> {
+    return batch
+        .get_statements()
+        .iter()
+        .filter_map(|(statement, _)| match statement {
+            BatchStatement::PreparedStatement(prepared) => Some(prepared),
+            BatchStatement::Query(_) => None,
+        });
+}
+
+pub fn prepared_statement(
+    result: ResultMessage,
+    query_text: &str,
+) -> Result<PreparedStatement, QueryError> {
+    match result {
+        ResultMessage::Prepared(prepared) => {
+            return Ok(PreparedStatement::new(
+                prepared.id,
+                prepared.result_metadata_id,
+                prepared.prepared_metadata,
+                prepared.result_metadata,
+                query_text.to_string(),
+            ))
+        }
+        _ => return Err(QueryError::UnexpectedResponse),
+    };
+}
+
+// Checked before sending, older servers would reject or misread the request
+pub fn check_protocol_version(
+    request: &Request,
+    features: &ConnectionFeatures,
+) -> Result<(), QueryError> {
+    let version = features.get_protocol_version();
+    if request.required_version() > version {
+        return Err(QueryError::UnsupportedByProtocolVersion(version));
+    }
+    return Ok(());
+}
+
+pub fn result_from_response(response: Response) -> Result<ResultMessage, QueryError> {
+    match response {
+        Response::Result(result) => return Ok(result),
+        Response::Error(message) => return Err(QueryError::Message(message)),
+        _ => return Err(QueryError::UnexpectedResponse),
+    };
+}
+
+// Server has evicted the statement from its cache when it answers Unprepared,
+// it's prepared again and the request retried once
+pub async fn execute(
+    sender: &mut impl StatementSender,
+    prepared_statement: &PreparedStatement,
+    values: SerializedValues,
+    paging_state: Option<Bytes>,
+) -> Result<ResultMessage, QueryError> {
+    let timeout = prepared_statement.get_timeout();
+    let request = execute_request(
+        prepared_statement,
+        prepared_statement,
+        values.clone(),
+        paging_state.clone(),
+        sender.get_config(),
+    );
+
+    let result = sender.send(request, timeout).await;
+    if !is_unprepared_error(&result) {
+        return result;
+    }
+
+    let reprepared = sender.prepare(prepared_statement.get_statement()).await?;
+    let request = execute_request(
+        prepared_statement,
+        &reprepared,
+        values,
+        paging_state,
+        sender.get_config(),
+    );
+    return sender.send(request, timeout).await;
+}
+
+// Statements were most likely evicted together, so all of them are prepared again
+pub async fn batch(
+    sender: &mut impl StatementSender,
+    batch: &Batch,
+) -> Result<ResultMessage, QueryError> {
+    let request = batch_request(batch, None, sender.get_config());
+
+    let result = sender.send(request, batch.get_timeout()).await;
+    if !is_unprepared_error(&result) {
+        return result;
+    }
+
+    let mut reprepared_ids = vec![];
+    for prepared in batch_prepared_statements(batch) {
+        let reprepared = sender.prepare(prepared.get_statement()).await?;
+        reprepared_ids.push(reprepared.get_id().to_vec());
+    }
+    let request = batch_request(batch, Some(&reprepared_ids), sender.get_config());
+    return sender.send(request, batch.get_timeout()).await;
+}
+
+// Server has evicted the prepared statement from its cache, e.g. after a restart
+pub fn is_unprepared_error<T>(result: &Result<T, QueryError>) -> bool {
+    return matches!(result, Err(QueryError::Message(error)) if error.is_unprepared());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::protocol::Header;
+    use crate::connection::{BatchType, Consistency, ProtocolVersion, SerialConsistency};
+    use bytes::BytesMut;
+
+    fn body(request: &Request) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        request.serialize(0, &mut buf, ProtocolVersion::V4, None);
+        return buf[Header::LENGTH..].to_vec();
+    }
+
+    #[test]
+    fn test_default_serial_consistency() {
+        let config = ConnectionConfig {
+            default_serial_consistency: Some(SerialConsistency::LocalSerial),
+            ..Default::default()
+        };
+
+        // Consistency ONE, flags with serial consistency, LOCAL_SERIAL
+        let mut query = Query::new("q");
+        let request = query_request(&query, SerializedValues::new(), None, &config);
+        assert_eq!(body(&request), [0u8, 0, 0, 1, b'q', 0, 1, 0x10, 0, 9]);
+
+        let mut batch = Batch::new(BatchType::Logged);
+        batch.append_statement(query.clone(), ()).unwrap();
+        let request = batch_request(&batch, None, &config);
+        assert!(body(&request).ends_with(&[0, 1, 0x10, 0, 9]));
+
+        query.set_serial_consistency(Some(SerialConsistency::Serial));
+        let request = query_request(&query, SerializedValues::new(), None, &config);
+        assert_eq!(body(&request), [0u8, 0, 0, 1, b'q', 0, 1, 0x10, 0, 8]);
+
+        // Without a default the flag isn't set
+        let request = query_request(
+            &Query::new("q"),
+            SerializedValues::new(),
+            None,
+            &Default::default(),
+        );
+        assert_eq!(body(&request), [0u8, 0, 0, 1, b'q', 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_consistency_can_be_reset_to_default() {
+        let config = ConnectionConfig {
+            default_consistency: Consistency::Quorum,
+            ..Default::default()
+        };
+
+        let mut query = Query::new("q");
+        query.set_consistency(Some(Consistency::All));
+        let request = query_request(&query, SerializedValues::new(), None, &config);
+        assert_eq!(body(&request)[5..7], [0, 5]);
+
+        query.set_consistency(None);
+        let request = query_request(&query, SerializedValues::new(), None, &config);
+        assert_eq!(body(&request)[5..7], [0, 4]);
+    }
+}
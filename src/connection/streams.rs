@@ -1,7 +1,10 @@
 use super::protocol;
+use super::QueryError;
 use protocol::types::StreamId;
+use std::convert::TryFrom;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
@@ -13,6 +16,8 @@ struct Stream {
     state: StreamState,
 }
 
+// Every state but Free holds the semaphore permit acquired when registering,
+// so the permit is released only when the stream goes back to the free list
 enum StreamState {
     Free,
     Registered {
@@ -21,46 +26,54 @@ enum StreamState {
     Sent {
         register_semaphore_permit: OwnedSemaphorePermit,
     },
+    // Request maker has dropped the handle, but the response is still to come,
+    // the stream can't be reused until it arrives
     SentButAbandoned {
-        #[allow(dead_code)]
         register_semaphore_permit: OwnedSemaphorePermit,
     },
+    // Response is boxed, inline it would make every one of the 32768 slots as large as itself
     Responded {
         register_semaphore_permit: OwnedSemaphorePermit,
-        response: protocol::Response,
+        response: Box<Result<protocol::Response, QueryError>>,
     },
     Finished {
-        #[allow(dead_code)]
         register_semaphore_permit: OwnedSemaphorePermit,
     },
 }
 
-fn print_state(state: &StreamState) {
-    match state {
-        StreamState::Free => println!("Free"),
-        StreamState::Registered { .. } => println!("Registered"),
-        StreamState::Sent { .. } => println!("Sent"),
-        StreamState::SentButAbandoned { .. } => println!("SentButAbandoned"),
-        StreamState::Responded { .. } => println!("Responded"),
-        StreamState::Finished { .. } => println!("Finished"),
-    };
+impl StreamState {
+    // Takes the state out, leaving Free in its place
+    // Every caller has to put the next state back
+    fn take(&mut self) -> StreamState {
+        return std::mem::replace(self, StreamState::Free);
+    }
 }
 
 type SharedStream = Arc<std::sync::Mutex<Stream>>;
 
 // StreamsManager coordinates assigning and freeing streams plus receiving messages
 pub struct StreamsManager {
+    // Indexed by stream id
     streams: Vec<SharedStream>,
-    free_streams: std::sync::Mutex<Vec<SharedStream>>,
+    // Also guards registering, so that no stream gets registered after the connection broke
+    free_streams: std::sync::Mutex<FreeStreams>,
     free_streams_semaphore: Arc<Semaphore>,
+    // Number of streams in SentButAbandoned state
+    orphaned_streams: AtomicUsize,
+}
+
+struct FreeStreams {
+    streams: Vec<SharedStream>,
+    // Set once reading from the connection failed, no new streams are given out after that
+    connection_error: Option<Arc<std::io::Error>>,
 }
 
 impl StreamsManager {
     pub fn new() -> Arc<StreamsManager> {
-        let total_streams_possible: usize = (StreamId::max_value() as usize) + 1;
+        let total_streams_possible: usize = (StreamId::MAX as usize) + 1;
 
         let mut streams: Vec<SharedStream> = Vec::with_capacity(total_streams_possible);
-        for i in (1..total_streams_possible).rev() {
+        for i in 0..total_streams_possible {
             streams.push(Arc::new(std::sync::Mutex::new(Stream {
                 id: i as StreamId,
                 response_waker: None,
@@ -68,95 +81,89 @@ impl StreamsManager {
             })));
         }
 
-        let free_streams: std::sync::Mutex<Vec<SharedStream>> =
-            std::sync::Mutex::new(Vec::with_capacity(total_streams_possible));
-        {
-            let locked_free_streams: &mut Vec<SharedStream> = &mut free_streams.lock().unwrap();
-            for stream in &streams {
-                locked_free_streams.push(stream.clone());
-            }
-        }
-        println!(
-            "Created free_streams size: {}",
-            free_streams.lock().unwrap().len()
-        );
+        // Popped from the back, so lowest ids are used first
+        let free_streams: Vec<SharedStream> = streams.iter().rev().cloned().collect();
 
         return Arc::new(StreamsManager {
             streams,
-            free_streams,
+            free_streams: std::sync::Mutex::new(FreeStreams {
+                streams: free_streams,
+                connection_error: None,
+            }),
             free_streams_semaphore: Arc::new(Semaphore::new(total_streams_possible)),
+            orphaned_streams: AtomicUsize::new(0),
         });
     }
 
-    pub async fn register_stream(self: &Arc<Self>) -> StreamHandle {
+    pub async fn register_stream(self: &Arc<Self>) -> Result<StreamHandle, QueryError> {
         // Wait until a free stream is available
         let register_semaphore_permit: OwnedSemaphorePermit =
             self.free_streams_semaphore.clone().acquire_owned().await;
 
+        let mut free_streams = self.free_streams.lock().unwrap();
+        if let Some(error) = &free_streams.connection_error {
+            return Err(QueryError::ConnectionBroken(error.clone()));
+        }
+
         // Take the stream and initialize it
-        let the_stream: SharedStream = self.free_streams.lock().unwrap().pop().unwrap();
+        // Permits and free streams are released together, so there is always one to take
+        let the_stream: SharedStream = free_streams.streams.pop().unwrap();
         {
             let locked_stream: &mut Stream = &mut the_stream.lock().unwrap();
             assert!(matches!(locked_stream.state, StreamState::Free));
-            assert!(matches!(locked_stream.response_waker, None));
+            assert!(locked_stream.response_waker.is_none());
 
             locked_stream.state = StreamState::Registered {
                 register_semaphore_permit,
             };
-
-            println!("register");
-            print_state(&locked_stream.state);
         }
 
-        return StreamHandle {
+        return Ok(StreamHandle {
             stream: the_stream,
             streams_manager: self.clone(),
-        };
+        });
     }
 
     pub fn on_response_received(
         self: &Arc<Self>,
-        response: protocol::Response,
+        response: Result<protocol::Response, QueryError>,
         stream_id: StreamId,
     ) {
-        let the_stream: &SharedStream = &self.streams[stream_id as usize];
+        // Negative stream ids are used by the server for events, which aren't requested
+        let the_stream: &SharedStream = match usize::try_from(stream_id) {
+            Ok(index) => &self.streams[index],
+            Err(_) => return,
+        };
 
         let mut waker_to_call: Option<Waker> = None;
         {
             let locked_stream: &mut Stream = &mut the_stream.lock().unwrap();
-            let mut stream_state: StreamState = StreamState::Free;
-            std::mem::swap(&mut stream_state, &mut locked_stream.state);
-
-            println!("Received response");
-            print_state(&stream_state);
 
-            match stream_state {
+            locked_stream.state = match locked_stream.state.take() {
                 StreamState::Sent {
                     register_semaphore_permit,
                 }
                 | StreamState::Registered {
                     register_semaphore_permit,
                 } => {
-                    locked_stream.state = StreamState::Responded {
-                        register_semaphore_permit,
-                        response,
-                    };
-
                     waker_to_call = locked_stream.response_waker.take();
-                    println!("Responded!");
+                    StreamState::Responded {
+                        register_semaphore_permit,
+                        response: Box::new(response),
+                    }
                 }
-                StreamState::SentButAbandoned { .. } => {
-                    println!("Freeing state!");
+                StreamState::SentButAbandoned {
+                    register_semaphore_permit,
+                } => {
                     // This stream has been abandoned by caller so let's just free it
                     locked_stream.response_waker = None;
-                    self.free_streams.lock().unwrap().push(the_stream.clone());
-                    // Semaphore register permit gets dropped here
+                    self.orphaned_streams.fetch_sub(1, Ordering::Relaxed);
+                    self.free_stream(the_stream, register_semaphore_permit);
+                    StreamState::Free
                 }
-                _ => {
-                    println!("{:?}", response);
-                    print_state(&stream_state);
-                    println!("Bad server response!");
-                    locked_stream.state = stream_state;
+                other_state => {
+                    // Response to a stream that isn't waiting for one, ignore it
+                    other_state
                 }
             };
         }
@@ -166,8 +173,77 @@ impl StreamsManager {
         }
     }
 
-    pub fn on_receive_error(self: &Arc<Self>, _error: std::io::Error) {
-        unimplemented!();
+    // Called when reading or writing fails, nothing more will be received after that,
+    // so every stream waiting for a response gets the error
+    // Only the first error is kept, the connection is already broken when another one comes
+    pub fn on_connection_error(self: &Arc<Self>, error: std::io::Error) {
+        let error = Arc::new(error);
+        {
+            let mut free_streams = self.free_streams.lock().unwrap();
+            if free_streams.connection_error.is_some() {
+                return;
+            }
+            free_streams.connection_error = Some(error.clone());
+        }
+
+        for the_stream in &self.streams {
+            let mut waker_to_call: Option<Waker> = None;
+            {
+                let locked_stream: &mut Stream = &mut the_stream.lock().unwrap();
+
+                locked_stream.state = match locked_stream.state.take() {
+                    StreamState::Registered {
+                        register_semaphore_permit,
+                    }
+                    | StreamState::Sent {
+                        register_semaphore_permit,
+                    } => {
+                        waker_to_call = locked_stream.response_waker.take();
+                        StreamState::Responded {
+                            register_semaphore_permit,
+                            response: Box::new(Err(QueryError::ConnectionBroken(error.clone()))),
+                        }
+                    }
+                    StreamState::SentButAbandoned {
+                        register_semaphore_permit,
+                    } => {
+                        self.orphaned_streams.fetch_sub(1, Ordering::Relaxed);
+                        self.free_stream(the_stream, register_semaphore_permit);
+                        StreamState::Free
+                    }
+                    other_state => other_state,
+                };
+            }
+
+            if let Some(waker) = waker_to_call {
+                waker.wake();
+            }
+        }
+
+        // Wake up those waiting for a free stream, they will find the connection broken
+        // Each of them releases its permit right away, passing it on to the next one
+        self.free_streams_semaphore.add_permits(1);
+    }
+
+    pub fn get_connection_error(&self) -> Option<Arc<std::io::Error>> {
+        return self.free_streams.lock().unwrap().connection_error.clone();
+    }
+
+    // Streams abandoned after sending, e.g. when the request timed out
+    // They can't be reused until the late response arrives
+    pub fn get_orphaned_streams_count(&self) -> usize {
+        return self.orphaned_streams.load(Ordering::Relaxed);
+    }
+
+    // Puts the stream back on the free list before releasing its permit,
+    // so that whoever acquires the permit finds a free stream
+    fn free_stream(&self, stream: &SharedStream, register_semaphore_permit: OwnedSemaphorePermit) {
+        self.free_streams
+            .lock()
+            .unwrap()
+            .streams
+            .push(stream.clone());
+        drop(register_semaphore_permit);
     }
 }
 
@@ -183,26 +259,19 @@ impl StreamHandle {
         return self.stream.lock().unwrap().id;
     }
 
+    // Must be called before the request is handed to the writer,
+    // otherwise dropping the handle could free a stream that awaits a response
     pub fn mark_request_sent(&self) {
         let locked_stream: &mut Stream = &mut self.stream.lock().unwrap();
-        println!("poll");
-        print_state(&locked_stream.state);
 
-        let mut stream_state: StreamState = StreamState::Free;
-        std::mem::swap(&mut stream_state, &mut locked_stream.state);
-
-        if let StreamState::Registered {
-            register_semaphore_permit,
-        } = stream_state
-        {
-            locked_stream.state = StreamState::Sent {
+        locked_stream.state = match locked_stream.state.take() {
+            StreamState::Registered {
                 register_semaphore_permit,
-            };
-        } else {
-            locked_stream.state = stream_state;
-        }
-
-        print_state(&locked_stream.state);
+            } => StreamState::Sent {
+                register_semaphore_permit,
+            },
+            other_state => other_state,
+        };
     }
 
     pub fn get_response(self) -> StreamResponseFuture {
@@ -214,47 +283,39 @@ impl StreamHandle {
 
 impl Drop for StreamHandle {
     fn drop(&mut self) {
-        println!("Dropping handle!");
-
         let locked_stream: &mut Stream = &mut self.stream.lock().unwrap();
         locked_stream.response_waker = None;
 
-        let mut stream_state: StreamState = StreamState::Free;
-        std::mem::swap(&mut stream_state, &mut locked_stream.state);
-
-        match stream_state {
-            StreamState::Registered { .. } | StreamState::Finished { .. } => {
-                // Should be freed
-                // Put on free queue
-                println!("Freeing state!");
-                self.streams_manager
-                    .free_streams
-                    .lock()
-                    .unwrap()
-                    .push(self.stream.clone());
-                // Register semaphore permit gets dropped here
+        locked_stream.state = match locked_stream.state.take() {
+            StreamState::Registered {
+                register_semaphore_permit,
             }
-            StreamState::Responded { .. } => {
-                // Should be freed
-                // Put on free queue
-                println!("Freeing state!");
+            | StreamState::Responded {
+                register_semaphore_permit,
+                ..
+            }
+            | StreamState::Finished {
+                register_semaphore_permit,
+            } => {
+                // Nothing more will be received on this stream, it can be reused
                 self.streams_manager
-                    .free_streams
-                    .lock()
-                    .unwrap()
-                    .push(self.stream.clone());
-                // Register semaphore permit gets dropped here
+                    .free_stream(&self.stream, register_semaphore_permit);
+                StreamState::Free
             }
             StreamState::Sent {
                 register_semaphore_permit,
             } => {
-                // Should be marked as Abandoned
-                locked_stream.state = StreamState::SentButAbandoned {
+                // Response is still to come, the stream will be freed when it arrives
+                self.streams_manager
+                    .orphaned_streams
+                    .fetch_add(1, Ordering::Relaxed);
+                StreamState::SentButAbandoned {
                     register_semaphore_permit,
-                };
+                }
+            }
+            StreamState::Free | StreamState::SentButAbandoned { .. } => {
+                unreachable!("StreamHandle exists for a stream that isn't in use")
             }
-            StreamState::Free => unreachable!(),
-            StreamState::SentButAbandoned { .. } => unreachable!(),
         };
     }
 }
@@ -264,45 +325,36 @@ pub struct StreamResponseFuture {
 }
 
 impl Future for StreamResponseFuture {
-    type Output = Result<protocol::Response, std::io::Error>;
+    type Output = Result<protocol::Response, QueryError>;
 
     fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut result: Option<protocol::Response> = None;
+        let mut result: Option<Self::Output> = None;
 
         if let Some(stream_handle) = &self.stream_handle {
             let locked_stream: &mut Stream = &mut stream_handle.stream.lock().unwrap();
 
-            println!("poll");
-            print_state(&locked_stream.state);
-
-            let mut stream_state: StreamState = StreamState::Free;
-            std::mem::swap(&mut stream_state, &mut locked_stream.state);
-
-            match stream_state {
+            locked_stream.state = match locked_stream.state.take() {
                 StreamState::Responded {
                     register_semaphore_permit,
                     response,
                 } => {
-                    result = Some(response);
-                    locked_stream.state = StreamState::Finished {
+                    result = Some(*response);
+                    StreamState::Finished {
                         register_semaphore_permit,
-                    };
+                    }
                 }
-                StreamState::Finished { .. } => unreachable!(),
-                _ => {
+                other_state => {
                     locked_stream.response_waker = Some(context.waker().clone());
-                    locked_stream.state = stream_state;
+                    other_state
                 }
             };
-
-            print_state(&locked_stream.state);
         } else {
             panic!("StreamResponseFuture polled after completion");
         }
 
         if let Some(response) = result {
             self.stream_handle = None; // Explicitly drop the handle
-            return Poll::Ready(Ok(response));
+            return Poll::Ready(response);
         }
 
         return Poll::Pending;
@@ -0,0 +1,193 @@
+// Helpers for tests that talk to a fake server over a real socket
+
+use super::protocol::{segment, Header};
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub const READY: u8 = 0x02;
+pub const AUTHENTICATE: u8 = 0x03;
+pub const RESULT: u8 = 0x08;
+pub const ERROR: u8 = 0x00;
+pub const SUPPORTED: u8 = 0x06;
+pub const AUTH_CHALLENGE: u8 = 0x0E;
+pub const AUTH_SUCCESS: u8 = 0x10;
+
+pub const VOID_RESULT: [u8; 4] = [0, 0, 0, 1];
+
+// Returns header and body of the request
+pub async fn read_frame(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> (Header, Vec<u8>) {
+    let mut header = [0u8; Header::LENGTH];
+    stream.read_exact(&mut header).await.unwrap();
+    let header = Header::deserialize(&mut &header[..]).unwrap();
+    let mut body = vec![0u8; header.body_length as usize];
+    stream.read_exact(&mut body).await.unwrap();
+    return (header, body);
+}
+
+// Returns stream id, opcode and body of the request
+pub async fn read_request(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> (i16, u8, Vec<u8>) {
+    let (header, body) = read_frame(stream).await;
+    return (header.stream_id, header.opcode, body);
+}
+
+pub async fn write_response(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    stream_id: i16,
+    opcode: u8,
+    body: &[u8],
+) {
+    write_versioned_response(stream, 0x84, stream_id, opcode, body).await;
+}
+
+pub async fn write_versioned_response(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    protocol_version: u8,
+    stream_id: i16,
+    opcode: u8,
+    body: &[u8],
+) {
+    let frame = response_frame(protocol_version, stream_id, opcode, body);
+    stream.write_all(&frame).await.unwrap();
+}
+
+fn response_frame(protocol_version: u8, stream_id: i16, opcode: u8, body: &[u8]) -> Vec<u8> {
+    let header = Header {
+        protocol_version,
+        flags: 0,
+        stream_id,
+        opcode,
+        body_length: body.len() as u32,
+    };
+    let mut frame = vec![];
+    header.write_to(&mut frame);
+    frame.extend_from_slice(body);
+    return frame;
+}
+
+// Reads a frame sent after the v5 handshake, in an uncompressed segment of its own
+pub async fn read_segmented_frame(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> (Header, Vec<u8>) {
+    // Segment header and its CRC24, then the payload and its CRC32
+    let mut segment = vec![0u8; 6];
+    stream.read_exact(&mut segment).await.unwrap();
+    let payload_length = u32::from_le_bytes([segment[0], segment[1], segment[2] & 0x01, 0]);
+    segment.resize(6 + payload_length as usize + 4, 0);
+    stream.read_exact(&mut segment[6..]).await.unwrap();
+
+    let mut segment = BytesMut::from(&segment[..]);
+    let payload = segment::decode_segment(&mut segment, None)
+        .unwrap()
+        .unwrap();
+    let header = Header::deserialize(&mut &payload[..Header::LENGTH]).unwrap();
+    return (header, payload[Header::LENGTH..].to_vec());
+}
+
+// Writes a v5 response in an uncompressed segment
+pub async fn write_segmented_response(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    stream_id: i16,
+    opcode: u8,
+    body: &[u8],
+) {
+    let frame = response_frame(0x85, stream_id, opcode, body);
+    let mut segments = BytesMut::new();
+    segment::encode_segments(&frame, &mut segments, None);
+    stream.write_all(&segments).await.unwrap();
+}
+
+// Accepts a connection and its handshake in the default protocol version
+pub async fn accept_connection(listener: &TcpListener) -> TcpStream {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    accept_startup(&mut stream).await;
+    return stream;
+}
+
+// Accepts a connection and rejects the version of its OPTIONS with an error
+// in the given server version, returns the rejected version
+pub async fn reject_protocol_version(listener: &TcpListener, server_version: u8) -> u8 {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    return reject_options(&mut stream, server_version).await;
+}
+
+// Same as reject_protocol_version, on an accepted stream
+pub async fn reject_options(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    server_version: u8,
+) -> u8 {
+    let (header, _) = read_frame(stream).await;
+    let message = format!(
+        "Invalid or unsupported protocol version ({})",
+        header.protocol_version
+    );
+    reject_options_with_message(stream, server_version, header.stream_id, &message).await;
+    return header.protocol_version;
+}
+
+// Answers OPTIONS already read from the stream with a protocol error with the given message
+pub async fn reject_options_with_message(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    server_version: u8,
+    stream_id: i16,
+    message: &str,
+) {
+    let mut error = vec![0, 0, 0, 0x0A];
+    put_string(&mut error, message);
+    write_versioned_response(stream, server_version, stream_id, ERROR, &error).await;
+}
+
+// Answers OPTIONS with no options and STARTUP with READY
+pub async fn accept_startup(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) {
+    accept_startup_with_options(stream, &[]).await;
+}
+
+// Answers OPTIONS with SUPPORTED listing the given options and STARTUP with READY,
+// in the protocol version of the requests, returns body of the STARTUP request
+pub async fn accept_startup_with_options(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    supported: &[(&str, &[&str])],
+) -> Vec<u8> {
+    return answer_startup(stream, supported, READY, &[]).await;
+}
+
+// Answers OPTIONS with SUPPORTED listing the given options, returns version of the request
+pub async fn answer_options(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    supported: &[(&str, &[&str])],
+) -> u8 {
+    let (options_header, _) = read_frame(stream).await;
+    let version = options_header.protocol_version | Header::DIRECTION_RESPONSE;
+    let mut body = vec![];
+    // [string multimap]
+    body.put_u16(supported.len() as u16);
+    for (key, values) in supported {
+        put_string(&mut body, key);
+        body.put_u16(values.len() as u16);
+        for value in values.iter() {
+            put_string(&mut body, value);
+        }
+    }
+    write_versioned_response(stream, version, options_header.stream_id, SUPPORTED, &body).await;
+    return options_header.protocol_version;
+}
+
+// Like accept_startup_with_options, but STARTUP gets the given response
+pub async fn answer_startup(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    supported: &[(&str, &[&str])],
+    startup_opcode: u8,
+    startup_response: &[u8],
+) -> Vec<u8> {
+    let version = answer_options(stream, supported).await | Header::DIRECTION_RESPONSE;
+    let (stream_id, _, startup_body) = read_request(stream).await;
+    write_versioned_response(stream, version, stream_id, startup_opcode, startup_response).await;
+    return startup_body;
+}
+
+fn put_string(buf: &mut Vec<u8>, string: &str) {
+    buf.put_u16(string.len() as u16);
+    buf.put_slice(string.as_bytes());
+}
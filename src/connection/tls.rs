@@ -0,0 +1,302 @@
+// TLS on top of the TCP connection, with rustls
+// rustls streams implement the futures traits, they are adapted to the tokio ones with compat
+
+use futures_rustls::TlsConnector;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+    WebPKIVerifier,
+};
+use std::fmt;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_util::compat::{FuturesAsyncReadCompatExt, Tokio02AsyncReadCompatExt};
+use webpki::DNSNameRef;
+
+// Servers are trusted only if their certificates are signed by one of the added CAs
+#[derive(Clone)]
+pub struct TlsConfig {
+    client_config: ClientConfig,
+    server_name: String,
+    verify_hostname: bool,
+}
+
+impl TlsConfig {
+    // Name is sent in SNI and the server certificate has to be valid for it
+    pub fn new(server_name: impl Into<String>) -> TlsConfig {
+        return TlsConfig {
+            client_config: ClientConfig::new(),
+            server_name: server_name.into(),
+            verify_hostname: true,
+        };
+    }
+
+    // Trusts CA certificates from the PEM, fails if it has none
+    pub fn add_ca_certificates(&mut self, pem: &[u8]) -> Result<(), std::io::Error> {
+        let added = match self.client_config.root_store.add_pem_file(&mut &pem[..]) {
+            Ok((added, _)) => added,
+            Err(_) => 0,
+        };
+        if added == 0 {
+            return Err(make_tls_config_error("no valid CA certificates in PEM"));
+        }
+        return Ok(());
+    }
+
+    // Certificate sent to servers that require one, with its chain and private key in PEM,
+    // the key can be PKCS#8 or RSA
+    pub fn set_client_certificate(
+        &mut self,
+        certificate_chain_pem: &[u8],
+        private_key_pem: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let certificate_chain = pemfile::certs(&mut &certificate_chain_pem[..])
+            .map_err(|_| make_tls_config_error("invalid client certificate PEM"))?;
+        if certificate_chain.is_empty() {
+            return Err(make_tls_config_error("no client certificate in PEM"));
+        }
+
+        let mut keys = pemfile::pkcs8_private_keys(&mut &private_key_pem[..])
+            .map_err(|_| make_tls_config_error("invalid private key PEM"))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut &private_key_pem[..])
+                .map_err(|_| make_tls_config_error("invalid private key PEM"))?;
+        }
+        let key = match keys.into_iter().next() {
+            Some(key) => key,
+            None => return Err(make_tls_config_error("no private key in PEM")),
+        };
+
+        return self
+            .client_config
+            .set_single_client_cert(certificate_chain, key)
+            .map_err(|error| make_tls_config_error(&error.to_string()));
+    }
+
+    pub fn set_server_name(&mut self, server_name: impl Into<String>) {
+        self.server_name = server_name.into();
+    }
+
+    pub fn get_server_name(&self) -> &str {
+        return &self.server_name;
+    }
+
+    // Without it the certificate chain is still checked, but not the name it's issued for,
+    // e.g. for test clusters with one certificate shared by all nodes
+    pub fn set_verify_hostname(&mut self, verify_hostname: bool) {
+        self.verify_hostname = verify_hostname;
+    }
+
+    pub fn get_verify_hostname(&self) -> bool {
+        return self.verify_hostname;
+    }
+
+    pub(crate) async fn connect(
+        &self,
+        tcp_stream: TcpStream,
+    ) -> Result<impl AsyncRead + AsyncWrite + Send + Unpin, std::io::Error> {
+        let server_name = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .map_err(|_| make_tls_config_error("server name is not a valid DNS name"))?;
+
+        let mut client_config = self.client_config.clone();
+        if !self.verify_hostname {
+            client_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AnyHostnameVerifier));
+        }
+
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let tls_stream = connector
+            .connect(server_name, Tokio02AsyncReadCompatExt::compat(tcp_stream))
+            .await?;
+        return Ok(FuturesAsyncReadCompatExt::compat(tls_stream));
+    }
+}
+
+// Client config isn't printable, configs get logged
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f
+            .debug_struct("TlsConfig")
+            .field("server_name", &self.server_name)
+            .field("verify_hostname", &self.verify_hostname)
+            .finish();
+    }
+}
+
+// Verifies certificates like rustls does by default, except for the name
+struct AnyHostnameVerifier;
+
+impl ServerCertVerifier for AnyHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        // Name is checked last, after the chain is valid
+        match WebPKIVerifier::new().verify_server_cert(
+            roots,
+            presented_certs,
+            dns_name,
+            ocsp_response,
+        ) {
+            Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName)) => {
+                return Ok(ServerCertVerified::assertion())
+            }
+            result => return result,
+        };
+    }
+}
+
+fn make_tls_config_error(reason: &str) -> std::io::Error {
+    return std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("Invalid TLS config: {}", reason),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::test_utils::*;
+    use crate::{Connection, ConnectionConfig, Query};
+    use futures_rustls::TlsAcceptor;
+    use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, ServerConfig};
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    // Certificates signed by the CA, the server one is issued for localhost
+    const CA_PEM: &[u8] = include_bytes!("testdata/ca.pem");
+    const SERVER_PEM: &[u8] = include_bytes!("testdata/server.pem");
+    const SERVER_KEY_PEM: &[u8] = include_bytes!("testdata/server-key.pem");
+    const CLIENT_PEM: &[u8] = include_bytes!("testdata/client.pem");
+    const CLIENT_KEY_PEM: &[u8] = include_bytes!("testdata/client-key.pem");
+
+    // Accepts TLS connections and answers one query on each,
+    // connections failing the TLS handshake are skipped
+    async fn start_tls_server(require_client_certificate: bool) -> SocketAddr {
+        let client_verifier = if require_client_certificate {
+            let mut roots = RootCertStore::empty();
+            roots.add_pem_file(&mut &CA_PEM[..]).unwrap();
+            AllowAnyAuthenticatedClient::new(roots)
+        } else {
+            NoClientAuth::new()
+        };
+        let mut server_config = ServerConfig::new(client_verifier);
+        let certificate_chain = pemfile::certs(&mut &SERVER_PEM[..]).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut &SERVER_KEY_PEM[..]).unwrap();
+        server_config
+            .set_single_cert(certificate_chain, key[0].clone())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, _) = listener.accept().await.unwrap();
+                let tcp_stream = Tokio02AsyncReadCompatExt::compat(tcp_stream);
+                let tls_stream = match acceptor.accept(tcp_stream).await {
+                    Ok(tls_stream) => tls_stream,
+                    Err(_) => continue,
+                };
+                let mut stream = FuturesAsyncReadCompatExt::compat(tls_stream);
+
+                accept_startup(&mut stream).await;
+                let (stream_id, _, _) = read_request(&mut stream).await;
+                write_response(&mut stream, stream_id, RESULT, &VOID_RESULT).await;
+            }
+        });
+        return address;
+    }
+
+    fn config(tls_config: TlsConfig) -> ConnectionConfig {
+        return ConnectionConfig {
+            tls: Some(tls_config),
+            ..Default::default()
+        };
+    }
+
+    fn trusting_config(server_name: &str) -> TlsConfig {
+        let mut tls_config = TlsConfig::new(server_name);
+        tls_config.add_ca_certificates(CA_PEM).unwrap();
+        return tls_config;
+    }
+
+    #[test]
+    fn test_queries_over_tls() {
+        tokio_test::block_on(async {
+            let address = start_tls_server(false).await;
+
+            let connection =
+                Connection::new_with_config(address, config(trusting_config("localhost")))
+                    .await
+                    .unwrap();
+            connection.query(Query::new("a"), ()).await.unwrap();
+
+            // Server isn't trusted without its CA
+            let untrusting_config = config(TlsConfig::new("localhost"));
+            assert!(Connection::new_with_config(address, untrusting_config)
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_hostname_verification_can_be_skipped() {
+        tokio_test::block_on(async {
+            let address = start_tls_server(false).await;
+
+            let tls_config = trusting_config("db.example.com");
+            assert!(
+                Connection::new_with_config(address, config(tls_config.clone()))
+                    .await
+                    .is_err()
+            );
+
+            let mut tls_config = tls_config;
+            tls_config.set_verify_hostname(false);
+            let connection = Connection::new_with_config(address, config(tls_config))
+                .await
+                .unwrap();
+            connection.query(Query::new("a"), ()).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        tokio_test::block_on(async {
+            let address = start_tls_server(true).await;
+
+            let tls_config = trusting_config("localhost");
+            assert!(
+                Connection::new_with_config(address, config(tls_config.clone()))
+                    .await
+                    .is_err()
+            );
+
+            let mut tls_config = tls_config;
+            tls_config
+                .set_client_certificate(CLIENT_PEM, CLIENT_KEY_PEM)
+                .unwrap();
+            let connection = Connection::new_with_config(address, config(tls_config))
+                .await
+                .unwrap();
+            connection.query(Query::new("a"), ()).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_invalid_pem_is_rejected() {
+        let mut tls_config = TlsConfig::new("localhost");
+        assert!(tls_config
+            .add_ca_certificates(b"not a certificate")
+            .is_err());
+        assert!(tls_config
+            .set_client_certificate(CLIENT_PEM, b"not a key")
+            .is_err());
+    }
+}
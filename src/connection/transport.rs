@@ -0,0 +1,33 @@
+// Byte streams connections send frames over
+
+use super::ConnectionConfig;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+
+// TCP, TLS on top of it, a Unix socket or any stream given to the connection,
+// connections don't depend on which one it is
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+pub type TransportReadHalf = ReadHalf<Box<dyn Transport>>;
+pub type TransportWriteHalf = WriteHalf<Box<dyn Transport>>;
+
+// Wraps the TCP socket in TLS when the config has it
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+pub async fn open(
+    tcp_stream: TcpStream,
+    config: &ConnectionConfig,
+) -> Result<Box<dyn Transport>, std::io::Error> {
+    // Requests are already batched before writing, delaying them further only adds latency
+    tcp_stream.set_nodelay(true)?;
+
+    #[cfg(feature = "tls")]
+    {
+        if let Some(tls_config) = &config.tls {
+            return Ok(Box::new(tls_config.connect(tcp_stream).await?));
+        }
+    }
+
+    return Ok(Box::new(tcp_stream));
+}
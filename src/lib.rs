@@ -1,6 +1,17 @@
+#![allow(clippy::needless_return)]
+
 pub mod connection;
+pub mod prepared_statement;
 pub mod query;
 
-pub use connection::simple_connection::Connection;
+pub use connection::Connection;
+pub use connection::{Authenticator, AuthenticatorSession, PlainTextAuthenticator};
+pub use connection::{
+    ConnectionConfig, ConnectionFeatures, Consistency, ProtocolVersion, SerialConsistency,
+};
 pub use connection::QueryError;
-pub use query::Query;
+pub use connection::QueryResult;
+#[cfg(feature = "tls")]
+pub use connection::TlsConfig;
+pub use prepared_statement::PreparedStatement;
+pub use query::{Batch, BatchStatement, BatchType, Query};
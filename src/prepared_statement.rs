@@ -0,0 +1,106 @@
+use crate::connection::{Consistency, PreparedMetadata, ResultMetadata, SerialConsistency};
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct PreparedStatement {
+    id: Vec<u8>,
+    result_metadata_id: Vec<u8>,
+    metadata: PreparedMetadata,
+    result_metadata: ResultMetadata,
+    statement: String,
+    consistency: Option<Consistency>,
+    serial_consistency: Option<SerialConsistency>,
+    page_size: Option<i32>,
+    timeout: Option<Duration>,
+    now_in_seconds: Option<i32>,
+}
+
+impl PreparedStatement {
+    pub fn new(
+        id: Vec<u8>,
+        result_metadata_id: Vec<u8>,
+        metadata: PreparedMetadata,
+        result_metadata: ResultMetadata,
+        statement: String,
+    ) -> PreparedStatement {
+        return PreparedStatement {
+            id,
+            result_metadata_id,
+            metadata,
+            result_metadata,
+            statement,
+            consistency: None,
+            serial_consistency: None,
+            page_size: None,
+            timeout: None,
+            now_in_seconds: None,
+        };
+    }
+
+    pub fn get_id(&self) -> &[u8] {
+        return &self.id;
+    }
+
+    // Empty unless the statement was prepared with protocol v5 or newer
+    pub fn get_result_metadata_id(&self) -> &[u8] {
+        return &self.result_metadata_id;
+    }
+
+    pub fn get_statement(&self) -> &str {
+        return &self.statement;
+    }
+
+    pub fn get_prepared_metadata(&self) -> &PreparedMetadata {
+        return &self.metadata;
+    }
+
+    // Describes rows returned by executing the statement
+    pub fn get_result_metadata(&self) -> &ResultMetadata {
+        return &self.result_metadata;
+    }
+
+    // Overrides the default consistency of the connection, None goes back to the default
+    pub fn set_consistency(&mut self, consistency: Option<Consistency>) {
+        self.consistency = consistency;
+    }
+
+    pub fn get_consistency(&self) -> Option<Consistency> {
+        return self.consistency;
+    }
+
+    // Overrides the default serial consistency of the connection, None goes back to the default
+    pub fn set_serial_consistency(&mut self, serial_consistency: Option<SerialConsistency>) {
+        self.serial_consistency = serial_consistency;
+    }
+
+    pub fn get_serial_consistency(&self) -> Option<SerialConsistency> {
+        return self.serial_consistency;
+    }
+
+    // Maximum number of rows returned in a single page, None disables paging
+    pub fn set_page_size(&mut self, page_size: Option<i32>) {
+        self.page_size = page_size;
+    }
+
+    pub fn get_page_size(&self) -> Option<i32> {
+        return self.page_size;
+    }
+
+    // Overrides the request timeout of the connection
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        return self.timeout;
+    }
+
+    // Current time the server uses for TTLs and expiration, needs protocol v5
+    pub fn set_now_in_seconds(&mut self, now_in_seconds: Option<i32>) {
+        self.now_in_seconds = now_in_seconds;
+    }
+
+    pub fn get_now_in_seconds(&self) -> Option<i32> {
+        return self.now_in_seconds;
+    }
+}
@@ -1,15 +1,235 @@
+use crate::connection::{
+    Consistency, SerialConsistency, SerializeValuesError, SerializedValues, ValueList,
+};
+use crate::PreparedStatement;
+use std::time::Duration;
+
+pub use crate::connection::BatchType;
+
+#[derive(Clone)]
 pub struct Query {
     query_text: String,
+    consistency: Option<Consistency>,
+    serial_consistency: Option<SerialConsistency>,
+    page_size: Option<i32>,
+    timeout: Option<Duration>,
+    keyspace: Option<String>,
+    now_in_seconds: Option<i32>,
 }
 
 impl Query {
     pub fn new(query_text: &str) -> Query {
         return Query {
             query_text: query_text.to_string(),
+            consistency: None,
+            serial_consistency: None,
+            page_size: None,
+            timeout: None,
+            keyspace: None,
+            now_in_seconds: None,
         };
     }
 
     pub fn get_query_text(&self) -> String {
         return self.query_text.clone();
     }
+
+    // Overrides the default consistency of the connection, None goes back to the default
+    pub fn set_consistency(&mut self, consistency: Option<Consistency>) {
+        self.consistency = consistency;
+    }
+
+    pub fn get_consistency(&self) -> Option<Consistency> {
+        return self.consistency;
+    }
+
+    // Overrides the default serial consistency of the connection, None goes back to the default
+    pub fn set_serial_consistency(&mut self, serial_consistency: Option<SerialConsistency>) {
+        self.serial_consistency = serial_consistency;
+    }
+
+    pub fn get_serial_consistency(&self) -> Option<SerialConsistency> {
+        return self.serial_consistency;
+    }
+
+    // Maximum number of rows returned in a single page, None disables paging
+    pub fn set_page_size(&mut self, page_size: Option<i32>) {
+        self.page_size = page_size;
+    }
+
+    pub fn get_page_size(&self) -> Option<i32> {
+        return self.page_size;
+    }
+
+    // Overrides the request timeout of the connection
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        return self.timeout;
+    }
+
+    // Keyspace of tables the query doesn't qualify, instead of the one of the connection,
+    // needs protocol v5
+    pub fn set_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
+    }
+
+    pub fn get_keyspace(&self) -> Option<&str> {
+        return self.keyspace.as_deref();
+    }
+
+    // Current time the server uses for TTLs and expiration, needs protocol v5
+    pub fn set_now_in_seconds(&mut self, now_in_seconds: Option<i32>) {
+        self.now_in_seconds = now_in_seconds;
+    }
+
+    pub fn get_now_in_seconds(&self) -> Option<i32> {
+        return self.now_in_seconds;
+    }
+}
+
+#[derive(Clone)]
+pub enum BatchStatement {
+    Query(Query),
+    PreparedStatement(PreparedStatement),
+}
+
+impl From<Query> for BatchStatement {
+    fn from(query: Query) -> BatchStatement {
+        return BatchStatement::Query(query);
+    }
+}
+
+impl From<PreparedStatement> for BatchStatement {
+    fn from(prepared_statement: PreparedStatement) -> BatchStatement {
+        return BatchStatement::PreparedStatement(prepared_statement);
+    }
+}
+
+// Group of statements, each with its own values, sent in a single BATCH request
+#[derive(Clone)]
+pub struct Batch {
+    batch_type: BatchType,
+    statements: Vec<(BatchStatement, SerializedValues)>,
+    consistency: Option<Consistency>,
+    serial_consistency: Option<SerialConsistency>,
+    timestamp: Option<i64>,
+    timeout: Option<Duration>,
+    keyspace: Option<String>,
+    now_in_seconds: Option<i32>,
+}
+
+impl Batch {
+    pub fn new(batch_type: BatchType) -> Batch {
+        return Batch {
+            batch_type,
+            statements: vec![],
+            consistency: None,
+            serial_consistency: None,
+            timestamp: None,
+            timeout: None,
+            keyspace: None,
+            now_in_seconds: None,
+        };
+    }
+
+    // Values must be positional, named values are not supported in batches
+    pub fn append_statement(
+        &mut self,
+        statement: impl Into<BatchStatement>,
+        values: impl ValueList,
+    ) -> Result<(), SerializeValuesError> {
+        let values = values.serialized()?.into_owned();
+        if values.has_names() {
+            return Err(SerializeValuesError::NamedValuesInBatch);
+        }
+        if self.statements.len() == u16::MAX as usize {
+            return Err(SerializeValuesError::TooManyStatements);
+        }
+
+        self.statements.push((statement.into(), values));
+        return Ok(());
+    }
+
+    pub fn get_type(&self) -> BatchType {
+        return self.batch_type;
+    }
+
+    pub fn get_statements(&self) -> &[(BatchStatement, SerializedValues)] {
+        return &self.statements;
+    }
+
+    // Overrides the default consistency of the connection, None goes back to the default
+    pub fn set_consistency(&mut self, consistency: Option<Consistency>) {
+        self.consistency = consistency;
+    }
+
+    pub fn get_consistency(&self) -> Option<Consistency> {
+        return self.consistency;
+    }
+
+    // Overrides the default serial consistency of the connection, None goes back to the default
+    pub fn set_serial_consistency(&mut self, serial_consistency: Option<SerialConsistency>) {
+        self.serial_consistency = serial_consistency;
+    }
+
+    pub fn get_serial_consistency(&self) -> Option<SerialConsistency> {
+        return self.serial_consistency;
+    }
+
+    // Default timestamp for all statements, in microseconds since unix epoch
+    pub fn set_timestamp(&mut self, timestamp: Option<i64>) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn get_timestamp(&self) -> Option<i64> {
+        return self.timestamp;
+    }
+
+    // Overrides the request timeout of the connection
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        return self.timeout;
+    }
+
+    // Keyspace of tables the query doesn't qualify, instead of the one of the connection,
+    // needs protocol v5
+    pub fn set_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
+    }
+
+    pub fn get_keyspace(&self) -> Option<&str> {
+        return self.keyspace.as_deref();
+    }
+
+    // Current time the server uses for TTLs and expiration, needs protocol v5
+    pub fn set_now_in_seconds(&mut self, now_in_seconds: Option<i32>) {
+        self.now_in_seconds = now_in_seconds;
+    }
+
+    pub fn get_now_in_seconds(&self) -> Option<i32> {
+        return self.now_in_seconds;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_statements_limit() {
+        let mut batch = Batch::new(BatchType::Unlogged);
+        for _ in 0..u16::MAX {
+            batch.append_statement(Query::new("q"), ()).unwrap();
+        }
+        assert!(matches!(
+            batch.append_statement(Query::new("q"), ()),
+            Err(SerializeValuesError::TooManyStatements)
+        ));
+    }
 }